@@ -176,12 +176,14 @@ pub struct BroadcastInput {
 
 #[derive(Debug)]
 pub struct VariableInput {
+  pub name: String,
   pub is_global: bool,
   pub id: usize,
 }
 
 #[derive(Debug)]
 pub struct ListInput {
+  pub name: String,
   pub is_global: bool,
   pub id: usize,
 }
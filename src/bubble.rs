@@ -0,0 +1,161 @@
+use sdl2::ttf::Font;
+
+use crate::project::SayKind;
+
+/// Sizes from scratch-render's text bubbles, in pixels.
+pub const MAX_LINE_WIDTH: u32 = 170;
+pub const MIN_WIDTH: u32 = 50;
+pub const STROKE_WIDTH: u32 = 4;
+pub const PADDING: u32 = 10;
+pub const CORNER_RADIUS: f64 = 16.;
+pub const TAIL_HEIGHT: u32 = 12;
+pub const LINE_HEIGHT: u32 = 16;
+
+/// Splits `text` into lines no wider than `MAX_LINE_WIDTH`, breaking between words,
+/// or inside words that do not fit on a line of their own.
+pub fn wrap_text(font: &Font, text: &str) -> Vec<String> {
+  let width = |text: &str| font.size_of(text).map(|(width, _)| width).unwrap_or(0);
+  let mut lines = Vec::new();
+  let mut line = String::new();
+  for word in text.split(' ') {
+    let candidate = if line.is_empty() {
+      String::from(word)
+    } else {
+      format!("{line} {word}")
+    };
+    if width(&candidate) <= MAX_LINE_WIDTH {
+      line = candidate;
+      continue;
+    }
+    if !line.is_empty() {
+      lines.push(line);
+    }
+    line = String::from(word);
+    while width(&line) > MAX_LINE_WIDTH {
+      // The longest start of the word that fits, but at least one character.
+      let mut ends = line.char_indices().map(|(index, c)| index + c.len_utf8());
+      let first = ends.next().unwrap_or(line.len());
+      let end = ends
+        .take_while(|&end| width(&line[..end]) <= MAX_LINE_WIDTH)
+        .last()
+        .unwrap_or(first);
+      lines.push(line[..end].to_string());
+      line = line[end..].to_string();
+    }
+  }
+  if !line.is_empty() {
+    lines.push(line);
+  }
+  lines
+}
+
+/// Size of the bubble around text `text_width` pixels wide with `lines` lines,
+/// including the stroke and the tail.
+pub fn bubble_size(text_width: u32, lines: usize) -> (u32, u32) {
+  let (width, height) = padded_size(text_width, lines);
+  (width + STROKE_WIDTH, height + STROKE_WIDTH + TAIL_HEIGHT)
+}
+
+fn padded_size(text_width: u32, lines: usize) -> (u32, u32) {
+  (
+    text_width.max(MIN_WIDTH - 2 * PADDING) + 2 * PADDING,
+    LINE_HEIGHT * lines as u32 + 2 * PADDING,
+  )
+}
+
+/// RGBA pixels of an empty bubble with its tail in the bottom right corner, the
+/// size returned by `bubble_size`.
+pub fn bubble_pixels(kind: SayKind, text_width: u32, lines: usize) -> Vec<u8> {
+  let (width, height) = bubble_size(text_width, lines);
+  let (padded_width, padded_height) = padded_size(text_width, lines);
+  let (padded_width, padded_height) = (padded_width as f64, padded_height as f64);
+  let offset = STROKE_WIDTH as f64 / 2.;
+  let inside = |x: f64, y: f64| {
+    let (x, y) = (x - offset, y - offset);
+    // The rounded rectangle.
+    let corner_x = x.clamp(CORNER_RADIUS, padded_width - CORNER_RADIUS);
+    let corner_y = y.clamp(CORNER_RADIUS, padded_height - CORNER_RADIUS);
+    if (0. ..=padded_width).contains(&x)
+      && (0. ..=padded_height).contains(&y)
+      && (x - corner_x).hypot(y - corner_y) <= CORNER_RADIUS
+    {
+      return true;
+    }
+    // The tail, relative to where it leaves the bottom edge.
+    let (x, y) = (x - (padded_width - CORNER_RADIUS), y - padded_height);
+    match kind {
+      SayKind::Say => in_polygon(
+        x,
+        y,
+        &[
+          (0., 0.),
+          (3., 6.),
+          (4., 10.),
+          (3., 12.),
+          (1., 12.),
+          (-5., 9.),
+          (-12., 4.),
+          (-16., 0.),
+        ],
+      ),
+      SayKind::Think => {
+        (x + 16.).hypot(y) <= 4.
+          || (x + 9.25).hypot(y - 7.25) <= 2.25
+          || (x + 1.5).hypot(y - 9.5) <= 1.5
+      }
+    }
+  };
+  let fill = [255., 255., 255., 255.];
+  let inner_stroke = [217., 217., 217., 255.];
+  let outer_stroke = [0., 0., 0., 38.];
+  let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+  for y in 0..height {
+    for x in 0..width {
+      // Four samples per pixel, averaged with premultiplied alpha.
+      let mut sum = [0f64; 4];
+      for (sub_x, sub_y) in [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)] {
+        let (x, y) = (x as f64 + sub_x, y as f64 + sub_y);
+        let is_inside = inside(x, y);
+        let near_edge = (0..12).any(|step| {
+          let angle = step as f64 * std::f64::consts::PI / 6.;
+          inside(x + offset * angle.cos(), y + offset * angle.sin()) != is_inside
+        });
+        let color = match (is_inside, near_edge) {
+          (true, false) => fill,
+          (true, true) => inner_stroke,
+          (false, true) => outer_stroke,
+          (false, false) => [0.; 4],
+        };
+        for (sum, channel) in sum.iter_mut().zip(&color[..3]) {
+          *sum += channel * color[3] / 255.;
+        }
+        sum[3] += color[3];
+      }
+      let alpha = sum[3] / 4.;
+      for channel in &sum[..3] {
+        let value = if sum[3] == 0. {
+          0.
+        } else {
+          channel * 255. / sum[3]
+        };
+        pixels.push(value.round() as u8);
+      }
+      pixels.push(alpha.round() as u8);
+    }
+  }
+  pixels
+}
+
+fn in_polygon(x: f64, y: f64, points: &[(f64, f64)]) -> bool {
+  let mut inside = false;
+  let mut previous = points[points.len() - 1];
+  for &point in points {
+    if (point.1 > y) != (previous.1 > y)
+      && x < (previous.0 - point.0) * (y - point.1) / (previous.1 - point.1) + point.0
+    {
+      inside = !inside;
+    }
+    previous = point;
+  }
+  inside
+}
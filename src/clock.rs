@@ -0,0 +1,226 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Where a project gets its time from. It is read once at the start of every frame.
+pub trait Clock {
+  /// Seconds since the project started.
+  fn now(&mut self) -> f64;
+  /// Seconds since 1970-01-01 00:00 UTC.
+  fn unix_time(&mut self) -> f64;
+  /// Seconds to add to UTC to get the local time.
+  fn utc_offset(&mut self) -> f64;
+}
+
+/// Follows the wall clock and the local time zone.
+pub struct SystemClock {
+  start: Instant,
+}
+
+impl SystemClock {
+  pub fn new() -> SystemClock {
+    SystemClock {
+      start: Instant::now(),
+    }
+  }
+}
+
+impl Default for SystemClock {
+  fn default() -> Self {
+    SystemClock::new()
+  }
+}
+
+impl Clock for SystemClock {
+  fn now(&mut self) -> f64 {
+    self.start.elapsed().as_secs_f64()
+  }
+
+  fn unix_time(&mut self) -> f64 {
+    system_unix_time()
+  }
+
+  fn utc_offset(&mut self) -> f64 {
+    let time = system_unix_time() as libc::time_t;
+    unsafe {
+      let mut tm: libc::tm = std::mem::zeroed();
+      if libc::localtime_r(&time, &mut tm).is_null() {
+        0.
+      } else {
+        tm.tm_gmtoff as f64
+      }
+    }
+  }
+}
+
+/// Advances by exactly one frame every time it is read, starting from a fixed date in
+/// UTC, so that every run of a project sees the same times. The clock a project
+/// loads with starts at the current date, runs are only reproducible with a clock
+/// given to `Project::set_clock`.
+pub struct FrameClock {
+  frame_duration: f64,
+  time: f64,
+  unix_start: f64,
+}
+
+impl FrameClock {
+  pub fn new(frame_rate: u32, unix_start: f64) -> FrameClock {
+    FrameClock {
+      frame_duration: 1. / frame_rate as f64,
+      time: 0.,
+      unix_start,
+    }
+  }
+}
+
+impl Clock for FrameClock {
+  fn now(&mut self) -> f64 {
+    self.time += self.frame_duration;
+    self.time
+  }
+
+  fn unix_time(&mut self) -> f64 {
+    self.unix_start + self.time
+  }
+
+  fn utc_offset(&mut self) -> f64 {
+    0.
+  }
+}
+
+pub fn system_unix_time() -> f64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs_f64())
+    .unwrap_or(0.)
+}
+
+/// `SharedState::unix_time` of 2000-01-01 00:00 UTC.
+const UNIX_TIME_2000: f64 = 946684800.;
+
+/// Days since 2000-01-01 00:00 UTC, as reported by `sensing_dayssince2000`.
+pub fn days_since_2000(unix_time: f64) -> f64 {
+  (unix_time - UNIX_TIME_2000) / 86400.
+}
+
+/// A calendar date and time, as reported by `sensing_current`.
+#[derive(Debug, PartialEq)]
+pub struct DateTime {
+  pub year: i64,
+  /// 1 to 12.
+  pub month: i64,
+  /// 1 to 31.
+  pub date: i64,
+  /// 1 is Sunday, 7 is Saturday.
+  pub day_of_week: i64,
+  pub hour: i64,
+  pub minute: i64,
+  pub second: i64,
+}
+
+impl DateTime {
+  /// Splits seconds since 1970-01-01 00:00 into calendar fields, using Howard
+  /// Hinnant's `civil_from_days`.
+  pub fn from_seconds(seconds: f64) -> DateTime {
+    let seconds = seconds.floor() as i64;
+    let days = seconds.div_euclid(86400);
+    let time = seconds.rem_euclid(86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+      (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year =
+      day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let date = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+      month_index + 3
+    } else {
+      month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    DateTime {
+      year,
+      month,
+      date,
+      // 1970-01-01 was a Thursday.
+      day_of_week: (days + 4).rem_euclid(7) + 1,
+      hour: time / 3600,
+      minute: time % 3600 / 60,
+      second: time % 60,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn date_time(
+    (year, month, date): (i64, i64, i64),
+    day_of_week: i64,
+    (hour, minute, second): (i64, i64, i64),
+  ) -> DateTime {
+    DateTime {
+      year,
+      month,
+      date,
+      day_of_week,
+      hour,
+      minute,
+      second,
+    }
+  }
+
+  #[test]
+  fn from_seconds_epochs() {
+    assert_eq!(
+      DateTime::from_seconds(0.),
+      date_time((1970, 1, 1), 5, (0, 0, 0))
+    );
+    assert_eq!(
+      DateTime::from_seconds(-1.),
+      date_time((1969, 12, 31), 4, (23, 59, 59))
+    );
+    assert_eq!(
+      DateTime::from_seconds(UNIX_TIME_2000),
+      date_time((2000, 1, 1), 7, (0, 0, 0))
+    );
+  }
+
+  #[test]
+  fn from_seconds_leap_years() {
+    assert_eq!(
+      DateTime::from_seconds(951782400.),
+      date_time((2000, 2, 29), 3, (0, 0, 0))
+    );
+    assert_eq!(
+      DateTime::from_seconds(1709210096.9),
+      date_time((2024, 2, 29), 5, (12, 34, 56))
+    );
+    // 2100 is not a leap year.
+    assert_eq!(
+      DateTime::from_seconds(4107542399.),
+      date_time((2100, 2, 28), 1, (23, 59, 59))
+    );
+    assert_eq!(
+      DateTime::from_seconds(4107542400.),
+      date_time((2100, 3, 1), 2, (0, 0, 0))
+    );
+  }
+
+  #[test]
+  fn days_since_2000_epoch() {
+    assert_eq!(days_since_2000(UNIX_TIME_2000), 0.);
+    assert_eq!(days_since_2000(946814400.), 1.5);
+    assert_eq!(days_since_2000(0.), -10957.);
+  }
+
+  #[test]
+  fn frame_clock_is_reproducible() {
+    let mut clock = FrameClock::new(30, UNIX_TIME_2000);
+    assert_eq!(clock.now(), 1. / 30.);
+    assert_eq!(clock.now(), 2. / 30.);
+    assert_eq!(clock.unix_time(), UNIX_TIME_2000 + 2. / 30.);
+    assert_eq!(clock.utc_offset(), 0.);
+  }
+}
@@ -0,0 +1,324 @@
+use std::f64::consts::PI;
+
+use crate::project::Texture;
+
+/// Graphic effects of a target, in the units the looks blocks use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Effects {
+  pub color: f64,
+  pub fisheye: f64,
+  pub whirl: f64,
+  pub pixelate: f64,
+  pub mosaic: f64,
+  pub brightness: f64,
+  pub ghost: f64,
+}
+
+impl Effects {
+  /// Looks up an effect by the name used in the `EFFECT` field, like "GHOST".
+  pub fn get_mut(&mut self, name: &str) -> Option<&mut f64> {
+    match name.to_lowercase().as_str() {
+      "color" => Some(&mut self.color),
+      "fisheye" => Some(&mut self.fisheye),
+      "whirl" => Some(&mut self.whirl),
+      "pixelate" => Some(&mut self.pixelate),
+      "mosaic" => Some(&mut self.mosaic),
+      "brightness" => Some(&mut self.brightness),
+      "ghost" => Some(&mut self.ghost),
+      _ => None,
+    }
+  }
+
+  /// Keeps ghost and brightness in the ranges Scratch allows.
+  pub fn clamp(&mut self) {
+    self.ghost = self.ghost.clamp(0., 100.);
+    self.brightness = self.brightness.clamp(-100., 100.);
+  }
+
+  pub fn is_empty(&self) -> bool {
+    *self == Effects::default()
+  }
+}
+
+/// Applies every effect to the pixels of a costume, the same way the fragment shader
+/// of scratch-render does. Returns RGBA pixels of the same size.
+pub fn apply_effects(texture: &Texture, effects: &Effects) -> Vec<u8> {
+  let width = texture.width as usize;
+  let height = texture.height as usize;
+  let mut pixels = Vec::with_capacity(width * height * 4);
+  let color = (effects.color / 200.).rem_euclid(1.);
+  let fisheye = ((effects.fisheye + 100.) / 100.).max(0.);
+  let whirl = -effects.whirl * PI / 180.;
+  let pixelate = effects.pixelate.abs() / 10.;
+  let mosaic = ((effects.mosaic.abs() + 10.) / 10. + 0.5)
+    .floor()
+    .clamp(1., 512.);
+  let brightness = effects.brightness.clamp(-100., 100.) / 100.;
+  let ghost = 1. - effects.ghost.clamp(0., 100.) / 100.;
+  // Size of the costume in stage units, which is what pixelate works in.
+  let skin_width = width as f64 / texture.bitmap_resolution as f64;
+  let skin_height = height as f64 / texture.bitmap_resolution as f64;
+  for y in 0..height {
+    for x in 0..width {
+      let mut u = (x as f64 + 0.5) / width as f64;
+      let mut v = (y as f64 + 0.5) / height as f64;
+      if effects.mosaic != 0. {
+        u = (mosaic * u).fract();
+        v = (mosaic * v).fract();
+      }
+      if effects.pixelate != 0. {
+        let texel_width = skin_width / pixelate;
+        let texel_height = skin_height / pixelate;
+        u = ((u * texel_width).floor() + 0.5) / texel_width;
+        v = ((v * texel_height).floor() + 0.5) / texel_height;
+      }
+      if effects.whirl != 0. {
+        let (offset_u, offset_v) = (u - 0.5, v - 0.5);
+        let factor = (1. - offset_u.hypot(offset_v) / 0.5).max(0.);
+        let (sin, cos) = (whirl * factor * factor).sin_cos();
+        u = cos * offset_u + sin * offset_v + 0.5;
+        v = -sin * offset_u + cos * offset_v + 0.5;
+      }
+      if effects.fisheye != 0. {
+        let (vector_u, vector_v) = ((u - 0.5) / 0.5, (v - 0.5) / 0.5);
+        let length = vector_u.hypot(vector_v);
+        if length > 0. {
+          let r = length.min(1.).powf(fisheye) * length.max(1.);
+          u = 0.5 + r * vector_u / length * 0.5;
+          v = 0.5 + r * vector_v / length * 0.5;
+        }
+      }
+      // Sampled with the nearest texel, clamped to the edges.
+      let sample_x =
+        ((u * width as f64) as isize).clamp(0, width as isize - 1) as usize;
+      let sample_y =
+        ((v * height as f64) as isize).clamp(0, height as isize - 1) as usize;
+      let index = (sample_y * width + sample_x) * 4;
+      let mut rgb = [
+        texture.pixels[index] as f64 / 255.,
+        texture.pixels[index + 1] as f64 / 255.,
+        texture.pixels[index + 2] as f64 / 255.,
+      ];
+      let alpha = texture.pixels[index + 3] as f64 / 255.;
+      if effects.color != 0. {
+        let (mut hue, mut saturation, mut value) = rgb_to_hsv(rgb);
+        // Greys are given a little colour so that the hue change is visible.
+        if value < 0.11 / 2. {
+          (hue, saturation, value) = (0., 1., 0.11 / 2.);
+        } else if saturation < 0.09 {
+          (hue, saturation) = (0., 0.09);
+        }
+        rgb = hsv_to_rgb(((hue + color).rem_euclid(1.), saturation, value));
+      }
+      if effects.brightness != 0. {
+        rgb = rgb.map(|channel| (channel + brightness).clamp(0., 1.));
+      }
+      for channel in rgb {
+        pixels.push((channel * 255. + 0.5) as u8);
+      }
+      pixels.push((alpha * ghost * 255. + 0.5) as u8);
+    }
+  }
+  pixels
+}
+
+/// Hue, saturation and value, all from 0 to 1.
+pub fn rgb_to_hsv([r, g, b]: [f64; 3]) -> (f64, f64, f64) {
+  let max = r.max(g).max(b);
+  let chroma = max - r.min(g).min(b);
+  let hue = if chroma == 0. {
+    0.
+  } else if max == r {
+    ((g - b) / chroma).rem_euclid(6.) / 6.
+  } else if max == g {
+    ((b - r) / chroma + 2.) / 6.
+  } else {
+    ((r - g) / chroma + 4.) / 6.
+  };
+  let saturation = if max == 0. { 0. } else { chroma / max };
+  (hue, saturation, max)
+}
+
+pub fn hsv_to_rgb((hue, saturation, value): (f64, f64, f64)) -> [f64; 3] {
+  let sector = hue * 6.;
+  let chroma = value * saturation;
+  let x = chroma * (1. - (sector.rem_euclid(2.) - 1.).abs());
+  let (r, g, b) = match sector as u32 {
+    0 => (chroma, x, 0.),
+    1 => (x, chroma, 0.),
+    2 => (0., chroma, x),
+    3 => (0., x, chroma),
+    4 => (x, 0., chroma),
+    _ => (chroma, 0., x),
+  };
+  let m = value - chroma;
+  [r + m, g + m, b + m]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A costume `width` pixels wide made of the given RGBA pixels.
+  fn texture(width: u32, pixels: &[[u8; 4]]) -> Texture {
+    let height = pixels.len() as u32 / width;
+    Texture::new(1, width, height, pixels.concat(), 0., 0.)
+  }
+
+  fn apply(texture: &Texture, effects: Effects) -> Vec<[u8; 4]> {
+    apply_effects(texture, &effects)
+      .chunks(4)
+      .map(|pixel| pixel.try_into().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn hsv_round_trip() {
+    assert_eq!(rgb_to_hsv([1., 0., 0.]), (0., 1., 1.));
+    assert_eq!(rgb_to_hsv([0.5, 0.5, 0.5]), (0., 0., 0.5));
+    assert_eq!(hsv_to_rgb((0., 0., 0.)), [0., 0., 0.]);
+    for r in 0..=4 {
+      for g in 0..=4 {
+        for b in 0..=4 {
+          let rgb = [r as f64 / 4., g as f64 / 4., b as f64 / 4.];
+          let round_trip = hsv_to_rgb(rgb_to_hsv(rgb));
+          for (channel, expected) in round_trip.iter().zip(rgb) {
+            assert!((channel - expected).abs() < 1e-9, "{rgb:?} {round_trip:?}");
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn color_wraps_at_200() {
+    let red = texture(1, &[[255, 0, 0, 255]]);
+    let color = |color| {
+      apply(
+        &red,
+        Effects {
+          color,
+          ..Effects::default()
+        },
+      )
+    };
+    assert_eq!(color(200.), [[255, 0, 0, 255]]);
+    assert_eq!(color(-200.), [[255, 0, 0, 255]]);
+    assert_eq!(color(100.), [[0, 255, 255, 255]]);
+    assert_eq!(color(-100.), color(100.));
+    assert_eq!(color(300.), color(100.));
+  }
+
+  #[test]
+  fn color_tints_greys() {
+    let grey = texture(1, &[[128, 128, 128, 255]]);
+    let [r, g, b, _] = apply(
+      &grey,
+      Effects {
+        color: 100.,
+        ..Effects::default()
+      },
+    )[0];
+    assert!(g == b && r < g, "{r} {g} {b}");
+  }
+
+  #[test]
+  fn ghost_alpha() {
+    let opaque = texture(2, &[[10, 20, 30, 255], [10, 20, 30, 100]]);
+    let ghost = |ghost| {
+      apply(
+        &opaque,
+        Effects {
+          ghost,
+          ..Effects::default()
+        },
+      )
+    };
+    assert_eq!(ghost(50.), [[10, 20, 30, 128], [10, 20, 30, 50]]);
+    assert_eq!(ghost(100.), [[10, 20, 30, 0]; 2]);
+    assert_eq!(ghost(150.), ghost(100.));
+    assert_eq!(ghost(-50.), [[10, 20, 30, 255], [10, 20, 30, 100]]);
+  }
+
+  #[test]
+  fn brightness_clamp() {
+    let grey = texture(1, &[[200, 100, 0, 255]]);
+    let brightness = |brightness| {
+      apply(
+        &grey,
+        Effects {
+          brightness,
+          ..Effects::default()
+        },
+      )
+    };
+    assert_eq!(brightness(100.), [[255, 255, 255, 255]]);
+    assert_eq!(brightness(200.), brightness(100.));
+    assert_eq!(brightness(-100.), [[0, 0, 0, 255]]);
+    assert_eq!(brightness(40.), [[255, 202, 102, 255]]);
+  }
+
+  #[test]
+  fn effects_clamp() {
+    let mut effects = Effects {
+      ghost: 150.,
+      brightness: -150.,
+      color: 1000.,
+      ..Effects::default()
+    };
+    effects.clamp();
+    assert_eq!((effects.ghost, effects.brightness), (100., -100.));
+    assert_eq!(effects.color, 1000.);
+    effects.ghost = -1.;
+    effects.brightness = 150.;
+    effects.clamp();
+    assert_eq!((effects.ghost, effects.brightness), (0., 100.));
+  }
+
+  #[test]
+  fn pixelate_and_mosaic() {
+    let row = [
+      [0, 0, 0, 255],
+      [1, 1, 1, 255],
+      [2, 2, 2, 255],
+      [3, 3, 3, 255],
+    ];
+    let pixelated = apply(
+      &texture(4, &row),
+      Effects {
+        pixelate: 20.,
+        ..Effects::default()
+      },
+    );
+    assert_eq!(pixelated, [row[1], row[1], row[3], row[3]]);
+    // Two copies of the costume side by side, sampled at their centres.
+    let mosaic = apply(
+      &texture(2, &row),
+      Effects {
+        mosaic: 10.,
+        ..Effects::default()
+      },
+    );
+    assert_eq!(mosaic, [row[3]; 4]);
+  }
+
+  #[test]
+  fn whirl_and_fisheye_keep_the_centre() {
+    let pixels: Vec<[u8; 4]> = (0..25).map(|index| [index, 0, 0, 255]).collect();
+    let costume = texture(5, &pixels);
+    for effects in [
+      Effects {
+        whirl: 90.,
+        ..Effects::default()
+      },
+      Effects {
+        fisheye: 200.,
+        ..Effects::default()
+      },
+    ] {
+      let applied = apply(&costume, effects.clone());
+      assert_eq!(applied[12], pixels[12], "{effects:?}");
+      assert_ne!(applied, pixels, "{effects:?}");
+    }
+  }
+}
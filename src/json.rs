@@ -1,29 +1,164 @@
-use std::collections::{HashMap, LinkedList};
-use std::fs::File;
-use std::io::BufReader;
-
-use sdl2::image::LoadTexture;
-use sdl2::render::TextureCreator;
-use sdl2::video::WindowContext;
-use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg;
+use serde::de::{DeserializeOwned, Error as _};
 use serde::{Deserialize, Deserializer};
 
 use crate::block;
 use crate::block::CustomBlock;
 use crate::block::Value;
+use crate::clock::{system_unix_time, FrameClock};
+use crate::effects::Effects;
+use crate::monitor::{self, MonitorMode};
 use crate::project::Config;
 use crate::project::Texture;
 use crate::project::{self, SharedState};
+use crate::random::Random;
+use crate::sound::{decode_sound, Mixer, NullSink, SoundEffects};
 use crate::target::{self, PenState};
 use serde::de::SeqAccess;
 use serde::de::Visitor;
 use std::fmt;
 use std::fmt::Formatter;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// Everything that can go wrong while loading a project.
+#[derive(Debug)]
+pub enum LoadError {
+  Io(std::io::Error),
+  Archive(ZipError),
+  Json(serde_json::Error),
+  NoStage,
+  NoCostumes {
+    target: String,
+  },
+  Costume {
+    target: String,
+    costume: String,
+    reason: String,
+  },
+  Sound {
+    target: String,
+    sound: String,
+    reason: String,
+  },
+  RotationStyle {
+    target: String,
+    rotation_style: String,
+  },
+  Block {
+    target: String,
+    block: String,
+    opcode: String,
+    kind: BlockError,
+  },
+}
+
+/// The reason a single block could not be loaded.
+#[derive(Debug)]
+pub enum BlockError {
+  MissingBlock(String),
+  MissingInput(String),
+  InputType { input: String, kind: i32 },
+  FieldId { field: String },
+  UnknownArgument(String),
+  UnknownVariable(String),
+  UnknownList(String),
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    match self {
+      LoadError::Io(error) => write!(f, "could not read project: {error}"),
+      LoadError::Archive(error) => write!(f, "invalid .sb3 archive: {error}"),
+      LoadError::Json(error) => write!(f, "invalid project.json: {error}"),
+      LoadError::NoStage => write!(f, "project has no stage"),
+      LoadError::NoCostumes { target } => write!(f, "{target}: has no costumes"),
+      LoadError::Costume {
+        target,
+        costume,
+        reason,
+      } => write!(f, "{target}: could not load costume {costume:?}: {reason}"),
+      LoadError::Sound {
+        target,
+        sound,
+        reason,
+      } => write!(f, "{target}: could not load sound {sound:?}: {reason}"),
+      LoadError::RotationStyle {
+        target,
+        rotation_style,
+      } => write!(f, "{target}: unknown rotation style {rotation_style:?}"),
+      LoadError::Block {
+        target,
+        block,
+        opcode,
+        kind,
+      } => write!(f, "{target}: block {block} ({opcode}): {kind}"),
+    }
+  }
+}
+
+impl fmt::Display for BlockError {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    match self {
+      BlockError::MissingBlock(id) => write!(f, "references missing block {id}"),
+      BlockError::MissingInput(input) => write!(f, "has no {input} input"),
+      BlockError::InputType { input, kind } => {
+        write!(f, "input {input} has unknown type {kind}")
+      }
+      BlockError::FieldId { field } => {
+        write!(
+          f,
+          "field {field} has an id but is not a variable, list or broadcast"
+        )
+      }
+      BlockError::UnknownArgument(name) => write!(f, "unknown argument {name:?}"),
+      BlockError::UnknownVariable(id) => write!(f, "unknown variable {id}"),
+      BlockError::UnknownList(id) => write!(f, "unknown list {id}"),
+    }
+  }
+}
+
+impl std::error::Error for LoadError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      LoadError::Io(error) => Some(error),
+      LoadError::Archive(error) => Some(error),
+      LoadError::Json(error) => Some(error),
+      _ => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for LoadError {
+  fn from(error: std::io::Error) -> Self {
+    LoadError::Io(error)
+  }
+}
+
+impl From<ZipError> for LoadError {
+  fn from(error: ZipError) -> Self {
+    LoadError::Archive(error)
+  }
+}
+
+impl From<serde_json::Error> for LoadError {
+  fn from(error: serde_json::Error) -> Self {
+    LoadError::Json(error)
+  }
+}
 
 #[derive(Deserialize)]
 pub struct Project {
   targets: Vec<Target>,
-  // extensions: Vec<String>,
+  #[serde(default)]
+  monitors: Vec<Monitor>,
+  #[serde(default)]
+  extensions: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -36,9 +171,13 @@ struct Target {
   blocks: HashMap<String, Block>,
   current_costume: i32,
   costumes: Vec<Costume>,
-  // layer_order: i32,
+  #[serde(default)]
+  sounds: Vec<Sound>,
+  #[serde(default)]
+  layer_order: i32,
   volume: f64,
-  // tempo: f64,
+  #[serde(default = "default_tempo")]
+  tempo: f64,
   #[serde(default = "default_true")]
   visible: bool,
   #[serde(default = "default_f64")]
@@ -55,6 +194,35 @@ struct Target {
   rotation_style: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Monitor {
+  mode: String,
+  opcode: String,
+  params: HashMap<String, String>,
+  sprite_name: Option<String>,
+  #[serde(default)]
+  width: Option<f64>,
+  #[serde(default)]
+  height: Option<f64>,
+  #[serde(default)]
+  x: f64,
+  #[serde(default)]
+  y: f64,
+  #[serde(default)]
+  visible: bool,
+  #[serde(default)]
+  slider_min: f64,
+  #[serde(default = "default_slider_max")]
+  slider_max: f64,
+  #[serde(default = "default_true")]
+  is_discrete: bool,
+}
+
+fn default_slider_max() -> f64 {
+  100.
+}
+
 fn default_true() -> bool {
   true
 }
@@ -71,6 +239,10 @@ fn default_size() -> f64 {
   100.
 }
 
+fn default_tempo() -> f64 {
+  60.
+}
+
 fn default_direction() -> f64 {
   90.
 }
@@ -117,9 +289,8 @@ where
   D: Deserializer<'de>,
   T: DeserializeOwned,
 {
-  let json_string = <String>::deserialize(de).unwrap();
-  let array: T = serde_json::from_str(json_string.as_str()).unwrap();
-  Ok(array)
+  let json_string = <String>::deserialize(de)?;
+  serde_json::from_str(json_string.as_str()).map_err(D::Error::custom)
 }
 
 #[derive(Debug, Deserialize)]
@@ -132,6 +303,14 @@ struct Costume {
   rotation_center_x: f64,
   rotation_center_y: f64,
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sound {
+  name: String,
+  md5ext: String,
+  data_format: String,
+}
 #[derive(Debug)]
 pub struct Variable {
   pub name: String,
@@ -167,6 +346,8 @@ pub enum Input {
   Broadcast(BroadcastInput),
   Variable(VariableInput),
   List(ListInput),
+  /// An input whose primitive type is not known, reported once the block is known.
+  Unknown(i32),
 }
 
 #[derive(Debug)]
@@ -198,7 +379,7 @@ impl<'de> Deserialize<'de> for Field {
             }),
             _ => Ok(Field { value, id: None }),
           },
-          _ => panic!(),
+          None => Err(A::Error::invalid_length(0, &self)),
         }
       }
     }
@@ -226,68 +407,46 @@ impl<'de> Deserialize<'de> for Input {
           String(String),
           Values(Option<Vec<Value>>),
         }
-        let _shadow = seq.next_element::<i32>()?.unwrap();
-        match seq.next_element::<T>()? {
-          Some(T::String(string)) => {
-            while seq.next_element::<serde_json::Value>()?.is_some() {}
-            return Ok(Input::Block(string));
+        fn string<E: serde::de::Error>(values: &mut Vec<Value>) -> Result<String, E> {
+          if values.len() < 2 {
+            return Err(E::invalid_length(values.len(), &"a name and an id"));
           }
-          Some(T::Values(values)) => {
-            //
-            if values.is_none() {
-              while seq.next_element::<serde_json::Value>()?.is_some() {}
-              return Ok(Input::Value(Value::Float(0.)));
-            }
-            let mut values = values.unwrap();
-            match values[0].to_f64() as i32 {
-              4 | 5 | 6 | 7 | 8 | 9 | 10 => {
-                while seq.next_element::<serde_json::Value>()?.is_some() {}
-                return Ok(Input::Value(values.remove(1)));
-              }
-              11 => {
-                while seq.next_element::<serde_json::Value>()?.is_some() {}
-                return Ok(Input::Broadcast(BroadcastInput {
-                  name: match values.remove(1) {
-                    Value::String(string) => string,
-                    _ => panic!(),
-                  },
-                  id: match values.remove(1) {
-                    Value::String(string) => string,
-                    _ => panic!(),
-                  },
-                }));
-              }
-              12 => {
-                while seq.next_element::<serde_json::Value>()?.is_some() {}
-                return Ok(Input::Variable(VariableInput {
-                  name: match values.remove(1) {
-                    Value::String(string) => string,
-                    _ => panic!(),
-                  },
-                  id: match values.remove(1) {
-                    Value::String(string) => string,
-                    _ => panic!(),
-                  },
-                }));
-              }
-              13 => {
-                while seq.next_element::<serde_json::Value>()?.is_some() {}
-                return Ok(Input::List(ListInput {
-                  name: match values.remove(1) {
-                    Value::String(string) => string,
-                    _ => panic!(),
-                  },
-                  id: match values.remove(1) {
-                    Value::String(string) => string,
-                    _ => panic!(),
-                  },
-                }));
-              }
-              _ => panic!(),
-            }
+          match values.remove(1) {
+            Value::String(string) => Ok(string),
+            value => Err(E::custom(format!("expected a string, found {value:?}"))),
           }
-          None => panic!(),
         }
+        let Some(_shadow) = seq.next_element::<i32>()? else {
+          return Err(A::Error::invalid_length(0, &self));
+        };
+        let input = match seq.next_element::<T>()? {
+          Some(T::String(string)) => Input::Block(string),
+          Some(T::Values(None)) => Input::Value(Value::Float(0.)),
+          Some(T::Values(Some(mut values))) => {
+            let Some(kind) = values.first().map(|kind| kind.to_f64() as i32) else {
+              return Err(A::Error::invalid_length(0, &"a primitive"));
+            };
+            match kind {
+              4..=10 if values.len() > 1 => Input::Value(values.remove(1)),
+              11 => Input::Broadcast(BroadcastInput {
+                name: string(&mut values)?,
+                id: string(&mut values)?,
+              }),
+              12 => Input::Variable(VariableInput {
+                name: string(&mut values)?,
+                id: string(&mut values)?,
+              }),
+              13 => Input::List(ListInput {
+                name: string(&mut values)?,
+                id: string(&mut values)?,
+              }),
+              kind => Input::Unknown(kind),
+            }
+          }
+          None => return Err(A::Error::invalid_length(1, &self)),
+        };
+        while seq.next_element::<serde_json::Value>()?.is_some() {}
+        Ok(input)
       }
     }
     de.deserialize_seq(SeqVisitor)
@@ -312,22 +471,48 @@ pub struct ListInput {
   pub id: String,
 }
 
+fn block_error(
+  target: &Target,
+  id: &str,
+  block: &Block,
+  kind: BlockError,
+) -> LoadError {
+  LoadError::Block {
+    target: target.name.clone(),
+    block: id.to_string(),
+    opcode: block.opcode.clone(),
+    kind,
+  }
+}
+
 fn convert_argument_reporter(
-  blocks: &HashMap<String, Block>,
+  target: &Target,
+  reporter_id: &str,
   block: &Block,
-) -> Option<usize> {
+) -> Result<Option<usize>, LoadError> {
+  let blocks = &target.blocks;
+  let error = |kind| block_error(target, reporter_id, block, kind);
   // reporter block is rogue, no need to convert.
-  let Some(mut id) = block.parent.as_ref() else { return None };
+  let Some(mut id) = block.parent.as_ref() else {
+    return Ok(None);
+  };
 
   let custom_block_id;
   loop {
-    let block = &blocks[id];
+    let block = blocks
+      .get(id)
+      .ok_or_else(|| error(BlockError::MissingBlock(id.clone())))?;
     if block.opcode == "procedures_definition" {
-      if let Input::Block(id) = &block.inputs["custom_block"] {
+      if let Some(Input::Block(id)) = block.inputs.get("custom_block") {
         custom_block_id = id;
         break;
       } else {
-        panic!("procedures_definition must have a custom_block input.");
+        return Err(block_error(
+          target,
+          id,
+          block,
+          BlockError::MissingInput(String::from("custom_block")),
+        ));
       }
     }
     if let Some(parent_id) = &block.parent {
@@ -335,58 +520,114 @@ fn convert_argument_reporter(
     } else {
       // reporter block is contained inside a rogue stack of blocks, return random argument id.
       // This id should never be used!
-      return Some(0);
+      return Ok(Some(0));
     }
   }
-  let custom_block = &blocks[custom_block_id];
+  let custom_block = blocks
+    .get(custom_block_id)
+    .ok_or_else(|| error(BlockError::MissingBlock(custom_block_id.clone())))?;
 
-  let Value::String(argument_name) = &block.fields["VALUE"].value else { return None };
+  let Some(Field {
+    value: Value::String(argument_name),
+    ..
+  }) = block.fields.get("VALUE")
+  else {
+    return Ok(None);
+  };
 
   let index = custom_block
     .mutation
     .argumentnames
     .iter()
     .position(|x| x == argument_name)
-    .unwrap();
+    .ok_or_else(|| error(BlockError::UnknownArgument(argument_name.clone())))?;
 
-  Some(index)
+  Ok(Some(index))
 }
 
-pub fn load<'a>(
-  texture_creator: &'a TextureCreator<WindowContext>,
-  config: Config,
-) -> project::Project<'a> {
+fn read_file(
+  archive: &mut ZipArchive<Cursor<&[u8]>>,
+  name: &str,
+) -> Result<Vec<u8>, LoadError> {
+  let mut file = archive.by_name(name)?;
+  let mut buffer = Vec::with_capacity(file.size() as usize);
+  file.read_to_end(&mut buffer)?;
+  Ok(buffer)
+}
+
+/// Decodes a PNG, JPEG or SVG costume into RGBA pixels.
+fn decode_image(bytes: &[u8]) -> Result<(u32, u32, Vec<u8>), String> {
+  if image::guess_format(bytes).is_err() {
+    return decode_svg(bytes);
+  }
+  let image = image::load_from_memory(bytes)
+    .map_err(|error| error.to_string())?
+    .into_rgba8();
+  Ok((image.width(), image.height(), image.into_raw()))
+}
+
+/// Ids of the Scratch extensions with blocks Rustphorus can run.
+const SUPPORTED_EXTENSIONS: [&str; 2] = ["pen", "music"];
+
+/// A saved list monitor size, `0.` for the default size, or at least
+/// `monitor::MIN_LIST_SIZE`.
+fn list_monitor_size(size: Option<f64>) -> f64 {
+  match size {
+    Some(size) if size > 0. => size.max(monitor::MIN_LIST_SIZE),
+    _ => 0.,
+  }
+}
+
+/// Rasterises an SVG at its own size.
+fn decode_svg(bytes: &[u8]) -> Result<(u32, u32, Vec<u8>), String> {
+  let tree = usvg::Tree::from_data(bytes, &usvg::Options::default())
+    .map_err(|error| error.to_string())?;
+  let size = tree.size().to_int_size();
+  let mut pixmap =
+    Pixmap::new(size.width(), size.height()).ok_or_else(|| "empty SVG".to_string())?;
+  resvg::render(&tree, Transform::default(), &mut pixmap.as_mut());
+  // tiny-skia premultiplies alpha, textures do not.
+  let pixels = pixmap
+    .pixels()
+    .iter()
+    .flat_map(|pixel| {
+      let color = pixel.demultiply();
+      [color.red(), color.green(), color.blue(), color.alpha()]
+    })
+    .collect();
+  Ok((size.width(), size.height(), pixels))
+}
+
+/// Reads project.json, with the fields of its blocks converted into inputs.
+fn read_project(archive: &mut ZipArchive<Cursor<&[u8]>>) -> Result<Project, LoadError> {
   let mut json_project: Project =
-    serde_json::from_reader(BufReader::new(File::open("tmp/project.json").unwrap()))
-      .unwrap();
+    serde_json::from_slice(&read_file(archive, "project.json")?)?;
+  for extension in &json_project.extensions {
+    if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+      log::warn!("unsupported extension {extension:?}, its blocks will not run");
+    }
+  }
 
   // Convert fields into inputs
   for target in &mut json_project.targets {
     // Convert argument reporters
-    let apply: Vec<(String, usize)> = target
-      .blocks
-      .iter()
-      .filter_map(|(id, block)| {
-        if block.opcode == "argument_reporter_string_number" {
-          if let Some(index) = convert_argument_reporter(&target.blocks, block) {
-            Some((id.clone(), index))
-          } else {
-            None
-          }
-        } else {
-          None
+    let mut apply: Vec<(String, usize)> = Vec::new();
+    for (id, block) in &target.blocks {
+      if block.opcode == "argument_reporter_string_number" {
+        if let Some(index) = convert_argument_reporter(target, id, block)? {
+          apply.push((id.clone(), index));
         }
-      })
-      .collect();
+      }
+    }
     for (id, index) in apply {
-      target
-        .blocks
-        .get_mut(&id)
-        .unwrap()
-        .inputs
-        .insert(format!("VALUE"), Input::Value(Value::Float(index as f64)));
+      if let Some(block) = target.blocks.get_mut(&id) {
+        block.inputs.insert(
+          "VALUE".to_string(),
+          Input::Value(Value::Float(index as f64)),
+        );
+      }
     }
-    for block in target.blocks.values_mut() {
+    for (block_id, block) in &mut target.blocks {
       // Convert mutation into inputs
       if block.opcode == "procedures_call" {
         block.inputs.insert(
@@ -404,12 +645,12 @@ pub fn load<'a>(
           if let Some(id) = &field.id {
             if key == "VARIABLE" {
               Input::Variable(VariableInput {
-                name: format!("ghost"),
+                name: field.value.to_string(),
                 id: id.clone(),
               })
             } else if key == "LIST" {
               Input::List(ListInput {
-                name: format!("ghost"),
+                name: field.value.to_string(),
                 id: id.clone(),
               })
             } else if key == "BROADCAST_OPTION" {
@@ -418,7 +659,12 @@ pub fn load<'a>(
                 id: id.clone(),
               })
             } else {
-              panic!()
+              return Err(LoadError::Block {
+                target: target.name.clone(),
+                block: block_id.clone(),
+                opcode: block.opcode.clone(),
+                kind: BlockError::FieldId { field: key.clone() },
+              });
             }
           } else {
             Input::Value(field.value.clone())
@@ -427,19 +673,33 @@ pub fn load<'a>(
       }
     }
   }
+  Ok(json_project)
+}
+
+pub fn load(
+  archive: &mut ZipArchive<Cursor<&[u8]>>,
+  config: Config,
+) -> Result<project::Project, LoadError> {
+  let json_project = read_project(archive)?;
+  let random = Random::new(config.seed.unwrap_or_else(|| system_unix_time().to_bits()));
 
   let mut project = project::Project {
-    config,
-    target_name_to_target_index: HashMap::with_capacity(json_project.targets.len()), // DONE
     targets: Vec::with_capacity(json_project.targets.len()), // DONE
-    textures: Vec::new(),                                    // DONE
     shared_state: SharedState {
-      global_variables: Vec::new(),
-      global_lists: Vec::new(),
-      pen: LinkedList::new(),
+      target_name_to_target_index: HashMap::with_capacity(json_project.targets.len()),
+      next_id: json_project.targets.len(),
+      layers: Vec::with_capacity(json_project.targets.len()),
+      ..SharedState::new(config.stage_width, config.stage_height, random)
     },
+    say_listeners: Vec::new(),
+    drag: None,
+    mixer: Arc::new(Mutex::new(Mixer::new(Vec::new()))),
+    null_sink: Some(NullSink::default()),
+    clock: Box::new(FrameClock::new(config.frame_rate, system_unix_time())),
+    config,
   };
-  let json_stage = &json_project.targets[0];
+  let json_stage = json_project.targets.first().ok_or(LoadError::NoStage)?;
+  project.shared_state.tempo = json_stage.tempo;
   let mut global_variables_id_to_index: HashMap<&String, usize> =
     HashMap::with_capacity(json_stage.variables.len());
   let mut global_lists_id_to_index: HashMap<&String, usize> =
@@ -460,28 +720,70 @@ pub fn load<'a>(
     index += 1;
   }
   let mut costume_md5ext_to_texture_index: HashMap<&String, usize> = HashMap::new();
+  let mut sound_md5ext_to_sound_index: HashMap<&String, usize> = HashMap::new();
+  let mut sounds = Vec::new();
   for json_target in &json_project.targets {
+    // Targets always show one of their costumes.
+    if json_target.costumes.is_empty() {
+      return Err(LoadError::NoCostumes {
+        target: json_target.name.clone(),
+      });
+    }
     for costume in &json_target.costumes {
       let md5ext = &costume.md5ext;
+      if costume_md5ext_to_texture_index.contains_key(md5ext) {
+        continue;
+      }
+      let costume_error = |reason: String| LoadError::Costume {
+        target: json_target.name.clone(),
+        costume: costume.name.clone(),
+        reason,
+      };
+      let bytes =
+        read_file(archive, md5ext).map_err(|error| costume_error(error.to_string()))?;
+      let (width, height, pixels) = decode_image(&bytes).map_err(costume_error)?;
+      project.shared_state.textures.push(Texture::new(
+        costume.bitmap_resolution,
+        width,
+        height,
+        pixels,
+        costume.rotation_center_x,
+        costume.rotation_center_y,
+      ));
       costume_md5ext_to_texture_index
-        .entry(md5ext)
-        .or_insert_with(|| {
-          project.textures.push(Texture {
-            bitmap_resolution: costume.bitmap_resolution,
-            texture: texture_creator
-              .load_texture(format!("tmp/{md5ext}"))
-              .unwrap(),
-            rotation_center_x: costume.rotation_center_x,
-            rotation_center_y: costume.rotation_center_y,
-          });
-          project.textures.len() - 1
-        });
+        .insert(md5ext, project.shared_state.textures.len() - 1);
+    }
+    for sound in &json_target.sounds {
+      let md5ext = &sound.md5ext;
+      if sound_md5ext_to_sound_index.contains_key(md5ext) {
+        continue;
+      }
+      let sound_error = |reason: String| LoadError::Sound {
+        target: json_target.name.clone(),
+        sound: sound.name.clone(),
+        reason,
+      };
+      let bytes =
+        read_file(archive, md5ext).map_err(|error| sound_error(error.to_string()))?;
+      // Like in Scratch, a sound that does not decode is silent rather than an error.
+      let decoded = decode_sound(bytes, &sound.data_format).unwrap_or_else(|reason| {
+        log::warn!(
+          "{}: could not decode sound {:?}: {reason}",
+          json_target.name,
+          sound.name
+        );
+        crate::sound::Sound::default()
+      });
+      sounds.push(decoded);
+      sound_md5ext_to_sound_index.insert(md5ext, sounds.len() - 1);
     }
     project
+      .shared_state
       .target_name_to_target_index
       .insert(json_target.name.clone(), project.targets.len());
     project.targets.push(target::Target {
       data: target::TargetData {
+        name: json_target.name.clone(),
         is_stage: json_target.is_stage,
         blocks: Vec::new(),
         costume_index_to_name: Vec::with_capacity(json_target.costumes.len()), // DONE
@@ -489,16 +791,33 @@ pub fn load<'a>(
         costume_index_to_texture_index: HashMap::with_capacity(
           json_target.costumes.len(),
         ), // DONE
+        sound_name_to_index: json_target
+          .sounds
+          .iter()
+          .enumerate()
+          .map(|(index, sound)| (sound.name.clone(), index))
+          .collect(),
+        sound_index_to_sound_index: json_target
+          .sounds
+          .iter()
+          .map(|sound| sound_md5ext_to_sound_index[&sound.md5ext])
+          .collect(),
         custom_blocks: HashMap::new(),
+        variable_name_to_index: HashMap::with_capacity(json_target.variables.len()),
+        list_name_to_index: HashMap::with_capacity(json_target.lists.len()),
       },
       state: target::TargetState {
+        id: project.targets.len(),
+        is_clone: false,
+        deleted: false,
         pen: PenState {
           is_down: false,
-          size: 1,
-          r: 0,
-          g: 0,
-          b: 255,
-          a: 0,
+          size: 1.,
+          color: 66.66,
+          saturation: 100.,
+          brightness: 100.,
+          transparency: 0.,
+          shade: 50.,
           x: json_target.x,
           y: json_target.y,
         },
@@ -513,14 +832,23 @@ pub fn load<'a>(
           "all around" => target::RotationStyle::AllAround,
           "don't rotate" => target::RotationStyle::DontRotate,
           "left-right" => target::RotationStyle::LeftRight,
-          _ => panic!(),
+          rotation_style => {
+            return Err(LoadError::RotationStyle {
+              target: json_target.name.clone(),
+              rotation_style: rotation_style.to_string(),
+            })
+          }
         },
         volume: json_target.volume,
         variables: Vec::with_capacity(json_target.variables.len()), // DONE
         lists: Vec::with_capacity(json_target.lists.len()),         // DONE
         say: None,
+        effects: Effects::default(),
+        sound_effects: SoundEffects::default(),
+        instrument: 0,
       },
       scripts: Vec::new(),
+      clones: Vec::new(),
     });
     let target = project.targets.last_mut().unwrap();
 
@@ -536,21 +864,39 @@ pub fn load<'a>(
             .variables
             .resize(*index + 1, block::Value::Float(0.));
         }
-        target.state.variables[*index] = json_target.variables[*id].value.clone();
+        let variable = &json_target.variables[*id];
+        target.state.variables[*index] = variable.value.clone();
+        target
+          .data
+          .variable_name_to_index
+          .insert(variable.name.clone(), *index);
       }
       for (id, index) in &global_lists_id_to_index {
         if *index >= target.state.lists.len() {
           target.state.lists.resize(*index + 1, vec![]);
         }
-        target.state.lists[*index] = json_target.lists[*id].value.clone();
+        let list = &json_target.lists[*id];
+        target.state.lists[*index] = list.value.clone();
+        target
+          .data
+          .list_name_to_index
+          .insert(list.name.clone(), *index);
       }
     } else {
       for (id, variable) in &json_target.variables {
         variables_id_to_index.insert(&id, target.state.variables.len());
+        target
+          .data
+          .variable_name_to_index
+          .insert(variable.name.clone(), target.state.variables.len());
         target.state.variables.push(variable.value.clone());
       }
       for (id, list) in &json_target.lists {
         lists_id_to_index.insert(&id, target.state.lists.len());
+        target
+          .data
+          .list_name_to_index
+          .insert(list.name.clone(), target.state.lists.len());
         target.state.lists.push(list.value.clone());
       }
     }
@@ -563,98 +909,120 @@ pub fn load<'a>(
       index_to_id.push(id);
       index += 1;
     }
-    for id in index_to_id {
-      let block = &json_target.blocks[id];
+    for block_id in index_to_id {
+      let block = &json_target.blocks[block_id];
+      let error = |kind| block_error(json_target, block_id, block, kind);
+      let lookup = |id: &String| {
+        id_to_index
+          .get(id)
+          .copied()
+          .ok_or_else(|| error(BlockError::MissingBlock(id.clone())))
+      };
       // Filter custom blocks
       if block.opcode == "procedures_definition" {
-        if let Input::Block(id) = &block.inputs["custom_block"] {
-          let custom_block = &json_target.blocks[id];
+        if let Some(Input::Block(id)) = block.inputs.get("custom_block") {
+          let custom_block = json_target
+            .blocks
+            .get(id)
+            .ok_or_else(|| error(BlockError::MissingBlock(id.clone())))?;
           target.data.custom_blocks.insert(
             custom_block.mutation.proccode.clone(),
             CustomBlock {
-              next: block
-                .next
-                .as_ref()
-                .and_then(|next| Some(id_to_index[next]))
-                .unwrap_or(0),
+              next: match &block.next {
+                Some(next) => lookup(next)?,
+                None => 0,
+              },
               argument_ids: custom_block.mutation.argumentids.clone(),
               refresh: !custom_block.mutation.warp,
             },
           );
         }
       }
+      let mut inputs = HashMap::with_capacity(block.inputs.len());
+      for (key, input) in &block.inputs {
+        let input = match input {
+          Input::Value(value) => block::Input::Value(value.clone()),
+          Input::Block(id) => {
+            let input_block = json_target
+              .blocks
+              .get(id)
+              .ok_or_else(|| error(BlockError::MissingBlock(id.clone())))?;
+            if input_block.opcode == "argument_reporter_string_number" {
+              match input_block.inputs.get("VALUE") {
+                Some(Input::Value(Value::Float(index))) => {
+                  block::Input::Argument(*index as usize)
+                }
+                _ => {
+                  return Err(block_error(
+                    json_target,
+                    id,
+                    input_block,
+                    BlockError::MissingInput(String::from("VALUE")),
+                  ))
+                }
+              }
+            } else {
+              block::Input::Block(lookup(id)?)
+            }
+          }
+          Input::Broadcast(broadcast) => {
+            block::Input::Broadcast(block::BroadcastInput {
+              name: broadcast.name.clone(),
+              id: broadcast.id.clone(),
+            })
+          }
+          Input::Variable(variable) => {
+            block::Input::Variable(match variables_id_to_index.get(&variable.id) {
+              Some(id) => block::VariableInput {
+                name: variable.name.clone(),
+                is_global: false,
+                id: *id,
+              },
+              None => block::VariableInput {
+                name: variable.name.clone(),
+                is_global: true,
+                id: *global_variables_id_to_index.get(&variable.id).ok_or_else(
+                  || error(BlockError::UnknownVariable(variable.id.clone())),
+                )?,
+              },
+            })
+          }
+          Input::List(list) => {
+            block::Input::List(match lists_id_to_index.get(&list.id) {
+              Some(id) => block::ListInput {
+                name: list.name.clone(),
+                is_global: false,
+                id: *id,
+              },
+              None => block::ListInput {
+                name: list.name.clone(),
+                is_global: true,
+                id: *global_lists_id_to_index
+                  .get(&list.id)
+                  .ok_or_else(|| error(BlockError::UnknownList(list.id.clone())))?,
+              },
+            })
+          }
+          Input::Unknown(kind) => {
+            return Err(error(BlockError::InputType {
+              input: key.clone(),
+              kind: *kind,
+            }))
+          }
+        };
+        inputs.insert(key.clone(), input);
+      }
       target.data.blocks.push(block::Block {
         opcode: block.opcode.clone(),
         next: match &block.next {
-          Some(next) => id_to_index[&next],
+          Some(next) => lookup(next)?,
           None => 0,
         },
         // parent: match &block.parent {
         //   Some(parent) => id_to_index[&parent],
         //   None => 0,
         // },
-        inputs: block
-          .inputs
-          .iter()
-          .map(|(key, input)| {
-            (
-              key.clone(),
-              match input {
-                Input::Value(value) => block::Input::Value(value.clone()),
-                Input::Block(id) => {
-                  let input_block = &json_target.blocks[id];
-                  if input_block.opcode == "argument_reporter_string_number" {
-                    if let Input::Value(value) = &input_block.inputs["VALUE"] {
-                      if let Value::Float(index) = value {
-                        block::Input::Argument(*index as usize)
-                      } else {
-                        panic!("{input_block:#?}")
-                      }
-                    } else {
-                      panic!()
-                    }
-                  } else {
-                    block::Input::Block(id_to_index[&id])
-                  }
-                }
-                Input::Broadcast(broadcast) => {
-                  block::Input::Broadcast(block::BroadcastInput {
-                    name: broadcast.name.clone(),
-                    id: broadcast.id.clone(),
-                  })
-                }
-                Input::Variable(variable) => block::Input::Variable(
-                  variables_id_to_index
-                    .get(&variable.id)
-                    .and_then(|id| {
-                      Some(block::VariableInput {
-                        is_global: false,
-                        id: *id,
-                      })
-                    })
-                    .unwrap_or_else(|| block::VariableInput {
-                      is_global: true,
-                      id: global_variables_id_to_index[&variable.id],
-                    }),
-                ),
-                Input::List(list) => block::Input::List(
-                  lists_id_to_index
-                    .get(&list.id)
-                    .and_then(|id| {
-                      Some(block::ListInput {
-                        is_global: false,
-                        id: *id,
-                      })
-                    })
-                    .unwrap_or_else(|| block::ListInput {
-                      is_global: true,
-                      id: global_lists_id_to_index[&list.id],
-                    }),
-                ),
-              },
-            )
-          })
-          .collect(),
+        inputs,
       });
     }
     for (i, costume) in json_target.costumes.iter().enumerate() {
@@ -663,11 +1031,192 @@ pub fn load<'a>(
         .data
         .costume_name_to_index
         .insert(costume.name.clone(), i);
+      let texture_index = costume_md5ext_to_texture_index[&costume.md5ext];
       target
         .data
         .costume_index_to_texture_index
-        .insert(i, costume_md5ext_to_texture_index[&costume.md5ext]);
+        .insert(i, texture_index);
     }
   }
-  project
+  project.mixer.lock().unwrap().sounds = sounds;
+  if let Some(stage) = project.targets.iter().find(|target| target.data.is_stage) {
+    project.shared_state.backdrop = stage.state.current_costume;
+    project.shared_state.backdrop_names = stage.data.costume_index_to_name.clone();
+  }
+  // Only variable and list monitors are shown.
+  for json_monitor in &json_project.monitors {
+    let (name, mode) = match (json_monitor.opcode.as_str(), json_monitor.mode.as_str())
+    {
+      ("data_variable", mode) => (
+        json_monitor.params.get("VARIABLE"),
+        match mode {
+          "large" => MonitorMode::Large,
+          "slider" => MonitorMode::Slider {
+            min: json_monitor.slider_min,
+            max: json_monitor.slider_max,
+            is_discrete: json_monitor.is_discrete,
+          },
+          _ => MonitorMode::Default,
+        },
+      ),
+      ("data_listcontents", _) => (json_monitor.params.get("LIST"), MonitorMode::List),
+      _ => continue,
+    };
+    let Some(name) = name else {
+      continue;
+    };
+    project.shared_state.monitors.push(monitor::Monitor {
+      target: json_monitor.sprite_name.clone(),
+      name: name.clone(),
+      mode,
+      x: json_monitor.x,
+      y: json_monitor.y,
+      width: list_monitor_size(json_monitor.width),
+      height: list_monitor_size(json_monitor.height),
+      visible: json_monitor.visible,
+    });
+  }
+  // Sprites are layered by `layerOrder`, the stage always goes at the bottom.
+  let mut layers: Vec<(bool, i32, usize)> = json_project
+    .targets
+    .iter()
+    .zip(&project.targets)
+    .map(|(json_target, target)| {
+      (
+        !json_target.is_stage,
+        json_target.layer_order,
+        target.state.id,
+      )
+    })
+    .collect();
+  layers.sort();
+  project.shared_state.layers = layers.into_iter().map(|(_, _, id)| id).collect();
+  Ok(project)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+
+  use image::{ImageOutputFormat, RgbaImage};
+  use zip::write::FileOptions;
+  use zip::ZipWriter;
+
+  use super::*;
+
+  /// A .sb3 archive with the given files.
+  fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, contents) in files {
+      writer.start_file(*name, FileOptions::default()).unwrap();
+      writer.write_all(contents.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap().into_inner()
+  }
+
+  /// Reads the project.json of an archive with only that file.
+  fn read(json: &str) -> Result<Project, LoadError> {
+    let bytes = archive(&[("project.json", json)]);
+    read_project(&mut ZipArchive::new(Cursor::new(&bytes[..])).unwrap())
+  }
+
+  /// project.json with a stage that has the given blocks.
+  fn stage_with_blocks(blocks: &str) -> String {
+    format!(
+      r#"{{"targets": [{{
+        "isStage": true, "name": "Stage", "variables": {{}}, "lists": {{}},
+        "blocks": {blocks}, "currentCostume": 0, "costumes": [], "volume": 100
+      }}]}}"#
+    )
+  }
+
+  #[test]
+  fn decode_costumes() {
+    let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="3" height="2">
+      <rect width="1" height="2" fill="red" fill-opacity="0.5"/>
+    </svg>"#;
+    let (width, height, pixels) = decode_image(svg.as_bytes()).unwrap();
+    assert_eq!((width, height), (3, 2));
+    assert_eq!(pixels[..8], [255, 0, 0, 128, 0, 0, 0, 0]);
+
+    let image = RgbaImage::from_raw(2, 1, vec![1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    let decoded = decode_image(png.get_ref()).unwrap();
+    assert_eq!(decoded, (2, 1, vec![1, 2, 3, 4, 5, 6, 7, 8]));
+
+    assert!(decode_image(b"not an image").is_err());
+  }
+
+  #[test]
+  fn broken_archive() {
+    let mut bytes = archive(&[("project.json", "{}")]);
+    bytes.truncate(bytes.len() / 2);
+    let error = ZipArchive::new(Cursor::new(&bytes[..])).map_err(LoadError::from);
+    assert!(matches!(error, Err(LoadError::Archive(_))));
+  }
+
+  #[test]
+  fn missing_project_json() {
+    let bytes = archive(&[("costume.svg", "<svg/>")]);
+    let error = read_project(&mut ZipArchive::new(Cursor::new(&bytes[..])).unwrap());
+    assert!(matches!(
+      error,
+      Err(LoadError::Archive(ZipError::FileNotFound))
+    ));
+  }
+
+  #[test]
+  fn invalid_project_json() {
+    assert!(matches!(read("{"), Err(LoadError::Json(_))));
+    assert!(matches!(read(r#"{"targets": 1}"#), Err(LoadError::Json(_))));
+  }
+
+  #[test]
+  fn unknown_field_id() {
+    let json = stage_with_blocks(
+      r#"{"a": {
+        "opcode": "looks_switchbackdropto", "next": null, "parent": null,
+        "inputs": {}, "fields": {"BACKDROP": ["backdrop1", "id"]}
+      }}"#,
+    );
+    let Err(LoadError::Block { block, kind, .. }) = read(&json) else {
+      panic!("expected a block error");
+    };
+    assert_eq!(block, "a");
+    assert!(matches!(kind, BlockError::FieldId { field } if field == "BACKDROP"));
+  }
+
+  #[test]
+  fn no_costumes() {
+    let bytes = archive(&[("project.json", &stage_with_blocks("{}"))]);
+    let config = Config {
+      stage_width: 480,
+      stage_height: 360,
+      frame_rate: 30,
+      seed: None,
+    };
+    let error = load(
+      &mut ZipArchive::new(Cursor::new(&bytes[..])).unwrap(),
+      config,
+    );
+    assert!(
+      matches!(error, Err(LoadError::NoCostumes { target }) if target == "Stage")
+    );
+  }
+
+  #[test]
+  fn field_ids() {
+    let json = stage_with_blocks(
+      r#"{"a": {
+        "opcode": "data_setvariableto", "next": null, "parent": null,
+        "inputs": {}, "fields": {"VARIABLE": ["score", "id"]}
+      }}"#,
+    );
+    let project = read(&json).unwrap();
+    let block = &project.targets[0].blocks["a"];
+    assert!(
+      matches!(&block.inputs["VARIABLE"], Input::Variable(variable) if variable.id == "id")
+    );
+  }
 }
@@ -0,0 +1,48 @@
+//! Rustphorus runs Scratch 3 projects (.sb3 files).
+//!
+//! ```no_run
+//! use rustphorus::{Config, InputEvent, Project};
+//!
+//! let bytes = std::fs::read("project.sb3").unwrap();
+//! let config = Config {
+//!   stage_width: 480,
+//!   stage_height: 360,
+//!   frame_rate: 30,
+//!   seed: None,
+//! };
+//! let mut project = Project::load_from_bytes(&bytes, config).unwrap();
+//! project.on_say(|say| println!("{}: {}", say.target, say.message));
+//! project.send(InputEvent::GreenFlag);
+//! while project.is_running() {
+//!   project.execute_scripts();
+//! }
+//! println!("{:?}", project.variable("Stage", "score"));
+//! ```
+
+mod block;
+#[cfg(feature = "sdl")]
+mod bubble;
+mod clock;
+mod effects;
+mod json;
+mod monitor;
+mod music;
+mod pen;
+mod project;
+mod random;
+#[cfg(feature = "sdl")]
+mod renderer;
+mod script;
+mod sound;
+mod target;
+mod touching;
+
+pub use block::Value;
+pub use clock::{Clock, DateTime, FrameClock, SystemClock};
+pub use json::{BlockError, LoadError};
+pub use project::{Config, InputEvent, Project, SayEvent, SayKind};
+#[cfg(feature = "sdl")]
+pub use renderer::Renderer;
+#[cfg(feature = "sdl")]
+pub use sound::AudioOutput;
+pub use sound::Mixer;
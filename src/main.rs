@@ -1,64 +1,166 @@
-use project::{Config, Project};
+use rustphorus::{AudioOutput, Config, InputEvent, Project, Renderer, SystemClock};
+use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
 use std::env::args;
+use std::process::exit;
 use std::{thread::sleep, time::Duration};
 
-mod block;
-mod json;
-mod pen;
-mod project;
-mod script;
-mod target;
-
 fn main() {
   pretty_env_logger::init();
   let config = Config {
     stage_width: 480,
     stage_height: 360,
     frame_rate: 30,
+    seed: None,
+  };
+  let mut headless = false;
+  let mut path = None;
+  for arg in args().skip(1) {
+    match arg.as_str() {
+      "--headless" => headless = true,
+      _ => path = Some(arg),
+    }
+  }
+  let path = path.expect("no path given");
+  let mut project = match Project::load(path.as_str(), config) {
+    Ok(project) => project,
+    Err(error) => {
+      eprintln!("{path}: {error}");
+      exit(1);
+    }
   };
+  //println!("{project:#?}");
+  //panic!();
+  project.send(InputEvent::GreenFlag);
+  if headless {
+    run_headless(&mut project);
+  } else {
+    run_window(&mut project);
+  }
+}
+
+/// Steps the project until every script has finished, without opening a window.
+fn run_headless(project: &mut Project) {
+  project.on_say(|say| {
+    if !say.message.is_empty() {
+      println!("{}: {}", say.target, say.message);
+    }
+  });
+  while project.is_running() {
+    project.execute_scripts();
+  }
+}
+
+fn run_window(project: &mut Project) {
   let sdl_context = sdl2::init().unwrap();
   let ttf_context = sdl2::ttf::init().unwrap();
-  let font = ttf_context.load_font("font.ttf", 16).unwrap();
+  let font = ttf_context.load_font("font.ttf", 14).unwrap();
   let video_subsystem = sdl_context.video().unwrap();
   let window = video_subsystem
-    .window("Rustphorus", config.stage_width, config.stage_height)
+    .window(
+      "Rustphorus",
+      project.config.stage_width,
+      project.config.stage_height,
+    )
     .opengl()
     .position_centered()
     .build()
     .unwrap();
   let mut canvas = window.into_canvas().build().unwrap();
   let mut event_pump = sdl_context.event_pump().unwrap();
+  let desired = AudioSpecDesired {
+    freq: Some(44100),
+    channels: Some(2),
+    samples: Some(1024),
+  };
+  // Without an audio device the project keeps playing sounds into its null sink.
+  let audio = sdl_context.audio().and_then(|audio| {
+    audio.open_playback(None, &desired, |spec| {
+      AudioOutput(project.connect_audio(spec.freq as u32))
+    })
+  });
+  match &audio {
+    Ok(device) => device.resume(),
+    Err(error) => eprintln!("could not open audio device: {error}"),
+  }
   let texture_creator = canvas.texture_creator();
-  let mut project = Project::load(
-    args().nth(1).expect("no path given").as_str(),
-    &texture_creator,
-    config,
-  );
-  //println!("{project:#?}");
-  //panic!();
-  project.start_scripts();
+  let mut renderer = Renderer::new(&texture_creator);
+  project.set_clock(SystemClock::new());
   let duration = Duration::new(0, 1_000_000_000u32 / project.config.frame_rate);
-  // let pen = texture_creator
-  //   .create_texture(
-  //     None,
-  //     sdl2::render::TextureAccess::Streaming,
-  //     project.config.stage_width,
-  //     project.config.stage_height,
-  //   )
-  //   .unwrap();
+  // Last known mouse position, in window pixels.
+  let mut mouse = (0, 0);
   'main: loop {
     for event in event_pump.poll_iter() {
       match event {
         Event::Quit { .. } => {
           break 'main;
         }
+        Event::KeyDown {
+          keycode: Some(keycode),
+          ..
+        } => {
+          if let Some(key) = scratch_key(keycode) {
+            project.send(InputEvent::KeyDown(key));
+          }
+        }
+        Event::KeyUp {
+          keycode: Some(keycode),
+          ..
+        } => {
+          if let Some(key) = scratch_key(keycode) {
+            project.send(InputEvent::KeyUp(key));
+          }
+        }
+        Event::MouseWheel { y, .. } => {
+          renderer.scroll(mouse.0, mouse.1, -y * 20);
+        }
+        Event::MouseMotion { x, y, .. } => {
+          mouse = (x, y);
+          project.send(InputEvent::MouseMove { x, y });
+        }
+        Event::MouseButtonDown {
+          mouse_btn: MouseButton::Left,
+          x,
+          y,
+          ..
+        } => {
+          project.send(InputEvent::MouseDown { x, y });
+        }
+        Event::MouseButtonUp {
+          mouse_btn: MouseButton::Left,
+          x,
+          y,
+          ..
+        } => {
+          project.send(InputEvent::MouseUp { x, y });
+        }
         _ => {}
       }
     }
     project.execute_scripts();
-    project.render(&mut canvas, &texture_creator, &font);
+    renderer.render(&mut canvas, &font, project);
     canvas.present();
     sleep(duration);
   }
 }
+
+/// The Scratch name of an SDL key, if Scratch knows about it.
+fn scratch_key(keycode: Keycode) -> Option<String> {
+  let key = match keycode {
+    Keycode::Space => "space",
+    Keycode::Left => "left arrow",
+    Keycode::Up => "up arrow",
+    Keycode::Right => "right arrow",
+    Keycode::Down => "down arrow",
+    Keycode::Return | Keycode::KpEnter => "enter",
+    keycode => {
+      let code = keycode as i32;
+      return (33..127)
+        .contains(&code)
+        .then(|| (code as u8 as char).to_ascii_lowercase().to_string());
+    }
+  };
+  Some(String::from(key))
+}
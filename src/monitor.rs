@@ -0,0 +1,37 @@
+/// List monitors saved smaller than this, in pixels, are drawn at this size, which
+/// fits their title and length bars.
+pub const MIN_LIST_SIZE: f64 = 50.;
+
+/// A variable or list monitor shown on the stage.
+#[derive(Debug)]
+// Only the renderer reads where monitors are.
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+pub struct Monitor {
+  /// Name of the sprite the variable or list belongs to, `None` for the stage's.
+  pub target: Option<String>,
+  pub name: String,
+  pub mode: MonitorMode,
+  /// Top left corner, in pixels from the top left corner of the stage.
+  pub x: f64,
+  pub y: f64,
+  /// Size of list monitors, `0.` to use the default size.
+  pub width: f64,
+  pub height: f64,
+  pub visible: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonitorMode {
+  /// The name of the variable next to its value.
+  Default,
+  /// Only the value.
+  Large,
+  /// Like `Default`, with a slider below.
+  Slider {
+    min: f64,
+    max: f64,
+    is_discrete: bool,
+  },
+  /// The items of a list.
+  List,
+}
@@ -0,0 +1,268 @@
+use std::f64::consts::TAU;
+
+/// Scratch keeps the tempo, in beats per minute, in this range.
+pub const MIN_TEMPO: f64 = 20.;
+pub const MAX_TEMPO: f64 = 500.;
+/// Scratch keeps note durations in this range of beats.
+pub const MAX_BEATS: f64 = 100.;
+/// MIDI note numbers Scratch can play.
+pub const MIN_NOTE: f64 = 0.;
+pub const MAX_NOTE: f64 = 130.;
+/// Scratch drops notes and drums while this many are already playing.
+pub const CONCURRENCY_LIMIT: usize = 30;
+
+/// A synthesised instrument: a sum of harmonics with an envelope.
+pub struct Instrument {
+  /// Amplitude of the fundamental and its overtones.
+  harmonics: &'static [f64],
+  /// Seconds until full volume.
+  attack: f64,
+  /// Time constant of the decay in seconds, or 0 for instruments that sustain.
+  decay: f64,
+  /// Seconds the note takes to fade out once its duration is over.
+  release: f64,
+}
+
+/// The instruments of the Music extension, in the order of its menu.
+pub const INSTRUMENTS: [Instrument; 21] = [
+  // Piano
+  instrument(&[1., 0.5, 0.3, 0.15, 0.1, 0.05], 0.005, 0.8, 0.5),
+  // Electric Piano
+  instrument(&[1., 0.2, 0.4, 0.05], 0.005, 1., 0.5),
+  // Organ
+  instrument(&[1., 0.8, 0.6, 0., 0.4, 0., 0.2, 0., 0.1], 0.01, 0., 0.1),
+  // Guitar
+  instrument(&[1., 0.6, 0.4, 0.3, 0.2, 0.1], 0.003, 0.6, 0.5),
+  // Electric Guitar
+  instrument(&[1., 0.7, 0.5, 0.4, 0.3, 0.2, 0.1], 0.003, 1.2, 0.5),
+  // Bass
+  instrument(&[1., 0.5, 0.2, 0.1], 0.005, 0.8, 0.25),
+  // Pizzicato
+  instrument(&[1., 0.5, 0.3, 0.2], 0.003, 0.25, 0.25),
+  // Cello
+  instrument(&[1., 0.7, 0.5, 0.4, 0.3, 0.2], 0.08, 0., 0.1),
+  // Trombone
+  instrument(&[1., 0.9, 0.7, 0.5, 0.3, 0.2], 0.05, 0., 0.05),
+  // Clarinet
+  instrument(&[1., 0., 0.5, 0., 0.3, 0., 0.15], 0.04, 0., 0.05),
+  // Saxophone
+  instrument(&[1., 0.8, 0.6, 0.5, 0.3, 0.2, 0.1], 0.04, 0., 0.05),
+  // Flute
+  instrument(&[1., 0.15, 0.05], 0.06, 0., 0.05),
+  // Wooden Flute
+  instrument(&[1., 0.3, 0.1], 0.04, 0., 0.05),
+  // Bassoon
+  instrument(&[1., 0.9, 0.8, 0.6, 0.4], 0.04, 0., 0.05),
+  // Choir
+  instrument(&[1., 0.4, 0.3, 0.2, 0.1], 0.15, 0., 0.25),
+  // Vibraphone
+  instrument(&[1., 0., 0., 0.3], 0.005, 1.5, 0.5),
+  // Music Box
+  instrument(&[1., 0., 0.2, 0., 0.1], 0.002, 0.6, 0.25),
+  // Steel Drum
+  instrument(&[1., 0.5, 0.4, 0.3], 0.005, 0.9, 0.5),
+  // Marimba
+  instrument(&[1., 0., 0., 0.25], 0.003, 0.35, 0.25),
+  // Synth Lead
+  instrument(&[1., 0., 0.33, 0., 0.2, 0., 0.14], 0.005, 0., 0.1),
+  // Synth Pad
+  instrument(&[1., 0.5, 0.33, 0.25, 0.2], 0.3, 0., 0.25),
+];
+
+const fn instrument(
+  harmonics: &'static [f64],
+  attack: f64,
+  decay: f64,
+  release: f64,
+) -> Instrument {
+  Instrument {
+    harmonics,
+    attack,
+    decay,
+    release,
+  }
+}
+
+/// A synthesised drum: a tone falling in pitch mixed with noise.
+pub struct Drum {
+  /// Frequency the tone settles at, in Hz.
+  frequency: f64,
+  /// How far above `frequency` the tone starts, in Hz.
+  sweep: f64,
+  tone: f64,
+  noise: f64,
+  /// Time constant of the decay, in seconds.
+  decay: f64,
+}
+
+/// The drums of the Music extension, in the order of its menu.
+pub const DRUMS: [Drum; 18] = [
+  // Snare Drum
+  drum(180., 60., 0.5, 0.8, 0.12),
+  // Bass Drum
+  drum(55., 120., 1., 0.05, 0.25),
+  // Side Stick
+  drum(400., 200., 0.6, 0.5, 0.03),
+  // Crash Cymbal
+  drum(0., 0., 0., 1., 0.8),
+  // Open Hi-Hat
+  drum(0., 0., 0., 0.7, 0.3),
+  // Closed Hi-Hat
+  drum(0., 0., 0., 0.7, 0.05),
+  // Tambourine
+  drum(0., 0., 0., 0.8, 0.15),
+  // Hand Clap
+  drum(0., 0., 0., 1., 0.08),
+  // Claves
+  drum(2500., 0., 1., 0., 0.04),
+  // Wood Block
+  drum(1000., 100., 1., 0.1, 0.05),
+  // Cowbell
+  drum(560., 0., 1., 0., 0.2),
+  // Triangle
+  drum(1500., 0., 0.6, 0., 1.),
+  // Bongo
+  drum(350., 80., 1., 0.1, 0.1),
+  // Conga
+  drum(220., 60., 1., 0.1, 0.15),
+  // Cabasa
+  drum(0., 0., 0., 0.6, 0.08),
+  // Guiro
+  drum(0., 0., 0., 0.6, 0.2),
+  // Vibraslap
+  drum(0., 0., 0., 0.6, 0.5),
+  // Cuica
+  drum(500., -200., 0.8, 0.1, 0.2),
+];
+
+const fn drum(frequency: f64, sweep: f64, tone: f64, noise: f64, decay: f64) -> Drum {
+  Drum {
+    frequency,
+    sweep,
+    tone,
+    noise,
+    decay,
+  }
+}
+
+/// Peak amplitude of notes and drums, leaving room to mix a few of them.
+const GAIN: f64 = 0.5;
+
+/// Index of a drum or instrument from its 1-based number, wrapping around the menu
+/// like Scratch does.
+pub fn menu_index(number: f64, count: usize) -> usize {
+  (number.round() as i64 - 1).rem_euclid(count as i64) as usize
+}
+
+/// Frequency of a MIDI note, in Hz.
+pub fn note_frequency(note: f64) -> f64 {
+  440. * 2f64.powf((note - 69.) / 12.)
+}
+
+impl Instrument {
+  /// The sample `time` seconds into a note held for `duration` seconds, or `None`
+  /// once it has faded out.
+  pub fn sample(&self, frequency: f64, time: f64, duration: f64) -> Option<f32> {
+    if time >= duration + self.release {
+      return None;
+    }
+    let mut envelope = (time / self.attack).min(1.);
+    if self.decay > 0. {
+      envelope *= (-time / self.decay).exp();
+    }
+    if time > duration {
+      envelope *= 1. - (time - duration) / self.release;
+    }
+    let total: f64 = self.harmonics.iter().sum();
+    let wave: f64 = self
+      .harmonics
+      .iter()
+      .enumerate()
+      .map(|(index, amplitude)| (frequency * (index + 1) as f64, amplitude))
+      // Overtones too high to hear would only alias.
+      .filter(|(overtone, _)| *overtone < 20000.)
+      .map(|(overtone, amplitude)| amplitude * (TAU * overtone * time).sin())
+      .sum();
+    Some((GAIN * envelope * wave / total) as f32)
+  }
+}
+
+impl Drum {
+  /// The sample `time` seconds after the drum was hit, or `None` once it has died
+  /// away.
+  pub fn sample(&self, time: f64) -> Option<f32> {
+    if time >= self.decay * 6. {
+      return None;
+    }
+    // The tone falls from `frequency + sweep` to `frequency`, this is its phase.
+    let fall = 30.;
+    let phase =
+      TAU * (self.frequency * time + self.sweep * (1. - (-fall * time).exp()) / fall);
+    let noise = noise((time * 44100.) as u64);
+    let envelope = (-time / self.decay).exp();
+    Some((GAIN * envelope * (self.tone * phase.sin() + self.noise * noise)) as f32)
+  }
+}
+
+/// White noise from -1 to 1, the same for the same `index`.
+fn noise(index: u64) -> f64 {
+  let mut x = index.wrapping_add(0x9e3779b97f4a7c15);
+  x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+  x ^= x >> 31;
+  x as f64 / u64::MAX as f64 * 2. - 1.
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn note_frequencies() {
+    assert_eq!(note_frequency(69.), 440.);
+    assert_eq!(note_frequency(81.), 880.);
+    assert!((note_frequency(60.) - 261.626).abs() < 0.001);
+  }
+
+  #[test]
+  fn menu_index_wraps() {
+    assert_eq!(menu_index(1., 18), 0);
+    assert_eq!(menu_index(18., 18), 17);
+    assert_eq!(menu_index(1.4, 18), 0);
+    assert_eq!(menu_index(0., 18), 17);
+    assert_eq!(menu_index(-1., 18), 16);
+    assert_eq!(menu_index(19., 18), 0);
+    assert_eq!(menu_index(40., 18), 3);
+  }
+
+  #[test]
+  fn instruments_end() {
+    let duration = 0.5;
+    for instrument in &INSTRUMENTS {
+      let end = duration + instrument.release;
+      let mut time = 0.;
+      while time < end {
+        let sample = instrument.sample(440., time, duration).unwrap();
+        assert!(sample.abs() <= 1., "{sample}");
+        time += 0.001;
+      }
+      assert_eq!(instrument.sample(440., end, duration), None);
+      assert_eq!(instrument.sample(440., end + 10., duration), None);
+    }
+  }
+
+  #[test]
+  fn drums_end() {
+    for drum in &DRUMS {
+      let end = drum.decay * 6.;
+      let mut time = 0.;
+      while time < end {
+        let sample = drum.sample(time).unwrap();
+        assert!(sample.abs() <= 1., "{sample}");
+        time += 0.001;
+      }
+      assert_eq!(drum.sample(end), None);
+      assert_eq!(drum.sample(end + 10.), None);
+    }
+  }
+}
@@ -1,10 +1,22 @@
-use std::collections::LinkedList;
+use crate::{
+  effects::{apply_effects, Effects},
+  project::Texture,
+  target::{RotationStyle, TargetData, TargetState},
+};
 
-use sdl2::{pixels::Color, render::Canvas, video::Window};
+/// Something to draw on the pen layer, queued in `SharedState::pen` while scripts
+/// run.
+#[derive(Debug)]
+pub enum PenInstruction {
+  Line(PenLine),
+  Stamp(Stamp),
+  Clear,
+}
 
 #[derive(Debug)]
-pub struct PenInstruction {
-  pub size: u32,
+pub struct PenLine {
+  /// Diameter of the pen, in pixels.
+  pub size: f64,
   pub r: u8,
   pub g: u8,
   pub b: u8,
@@ -15,30 +27,226 @@ pub struct PenInstruction {
   pub y2: f64,
 }
 
-pub fn render_pen(
-  stage_width: u32,
-  stage_height: u32,
-  canvas: &mut Canvas<Window>,
-  instructions: &LinkedList<PenInstruction>,
-) {
-  for line in instructions {
-    canvas.set_draw_color(Color {
-      r: line.r,
-      g: line.g,
-      b: line.b,
-      a: line.a,
-    });
-    canvas
-      .draw_line(
-        (
-          (line.x1 + stage_width as f64 / 2.) as i32,
-          (stage_height as f64 / 2. - line.y1) as i32,
-        ),
-        (
-          (line.x2 + stage_width as f64 / 2.) as i32,
-          (stage_height as f64 / 2. - line.y2) as i32,
-        ),
-      )
-      .unwrap();
+/// A costume drawn with the position, size, direction and effects its sprite had
+/// at the time.
+#[derive(Debug)]
+pub struct Stamp {
+  /// Index in `SharedState::textures`.
+  pub texture: usize,
+  pub x: f64,
+  pub y: f64,
+  pub size: f64,
+  pub direction: f64,
+  pub rotation_style: RotationStyle,
+  pub effects: Effects,
+}
+
+impl Stamp {
+  pub fn new(data: &TargetData, state: &TargetState) -> Stamp {
+    Stamp {
+      texture: data.costume_index_to_texture_index[&state.current_costume],
+      x: state.x,
+      y: state.y,
+      size: state.size,
+      direction: state.direction,
+      rotation_style: state.rotation_style.clone(),
+      effects: state.effects.clone(),
+    }
+  }
+
+  /// Bounds of the pixels of the costume that are not transparent, as they are
+  /// drawn, as (left, right, bottom, top) in stage coordinates. A costume without
+  /// such pixels has empty bounds at its position.
+  pub fn bounds(&self, texture: &Texture) -> (f64, f64, f64, f64) {
+    let Some((left, top, right, bottom)) = texture.opaque_bounds else {
+      return (self.x, self.x, self.y, self.y);
+    };
+    let scale = self.size / 100. / texture.bitmap_resolution as f64;
+    let (angle, flip) = self.angle_and_flip();
+    let (sin, cos) = angle.sin_cos();
+    let mut bounds = (
+      f64::INFINITY,
+      f64::NEG_INFINITY,
+      f64::INFINITY,
+      f64::NEG_INFINITY,
+    );
+    for (u, v) in [(left, top), (right, top), (left, bottom), (right, bottom)] {
+      // The inverse of `Stamp::texel`.
+      let mut du = (u as f64 - texture.width as f64 / 2.) * scale;
+      if flip {
+        du = -du;
+      }
+      let dv = (v as f64 - texture.height as f64 / 2.) * scale;
+      let x = self.x + du * cos - dv * sin;
+      let y = self.y - (du * sin + dv * cos);
+      bounds = (
+        bounds.0.min(x),
+        bounds.1.max(x),
+        bounds.2.min(y),
+        bounds.3.max(y),
+      );
+    }
+    bounds
+  }
+
+  /// How much the costume is rotated, in radians clockwise, and whether it is
+  /// mirrored.
+  fn angle_and_flip(&self) -> (f64, bool) {
+    match self.rotation_style {
+      RotationStyle::AllAround => ((self.direction - 90.).to_radians(), false),
+      RotationStyle::DontRotate => (0., false),
+      RotationStyle::LeftRight => (0., self.direction < 0.),
+    }
+  }
+
+  /// The pixel of `texture` drawn at the given stage coordinates, placed, scaled and
+  /// rotated the same way `Renderer::render` draws costumes.
+  pub fn texel(&self, texture: &Texture, x: f64, y: f64) -> Option<(u32, u32)> {
+    let scale = self.size / 100. / texture.bitmap_resolution as f64;
+    let (angle, flip) = self.angle_and_flip();
+    // Relative to the centre of the costume, with y pointing down.
+    let dx = x - self.x;
+    let dy = self.y - y;
+    let u = (dx * angle.cos() + dy * angle.sin()) / scale + texture.width as f64 / 2.;
+    let v = (dy * angle.cos() - dx * angle.sin()) / scale + texture.height as f64 / 2.;
+    if u < 0. || v < 0. || u >= texture.width as f64 || v >= texture.height as f64 {
+      return None;
+    }
+    let u = if flip {
+      texture.width - 1 - u as u32
+    } else {
+      u as u32
+    };
+    Some((u, v as u32))
+  }
+}
+
+/// Scratch clamps the pen size to this range.
+pub const MIN_PEN_SIZE: f64 = 1.;
+pub const MAX_PEN_SIZE: f64 = 1200.;
+
+/// What pen lines and stamps have drawn so far, between the backdrop and the
+/// sprites.
+#[derive(Debug)]
+pub struct PenLayer {
+  pub width: u32,
+  pub height: u32,
+  /// RGBA pixels the size of the stage, row by row.
+  pub pixels: Vec<u8>,
+  /// Counts the changes to `pixels`, so that they are only uploaded when needed.
+  pub version: usize,
+}
+
+impl PenLayer {
+  pub fn new(width: u32, height: u32) -> PenLayer {
+    PenLayer {
+      width,
+      height,
+      pixels: vec![0; (width * height * 4) as usize],
+      version: 0,
+    }
+  }
+
+  pub fn draw(&mut self, instruction: &PenInstruction, textures: &[Texture]) {
+    match instruction {
+      PenInstruction::Line(line) => self.draw_line(line),
+      PenInstruction::Stamp(stamp) => self.stamp(stamp, &textures[stamp.texture]),
+      PenInstruction::Clear => self.pixels.fill(0),
+    }
+    self.version += 1;
+  }
+
+  /// Draws a line with round caps, or a dot if both ends are the same. The edges
+  /// are anti-aliased over one pixel.
+  fn draw_line(&mut self, line: &PenLine) {
+    let (width, height) = (self.width, self.height);
+    // Odd sizes are moved by half a pixel so that they cover whole pixels, like the
+    // pen of Scratch.
+    let offset = if line.size as u32 % 2 == 1 { 0.5 } else { 0. };
+    let x1 = line.x1 + width as f64 / 2. + offset;
+    let y1 = height as f64 / 2. - line.y1 + offset;
+    let x2 = line.x2 + width as f64 / 2. + offset;
+    let y2 = height as f64 / 2. - line.y2 + offset;
+    let radius = line.size / 2.;
+    let clip = |value: f64, max: u32| (value.max(0.) as u32).min(max);
+    let left = clip(x1.min(x2) - radius - 1., width);
+    let right = clip((x1.max(x2) + radius + 1.).ceil(), width);
+    let top = clip(y1.min(y2) - radius - 1., height);
+    let bottom = clip((y1.max(y2) + radius + 1.).ceil(), height);
+    let (dx, dy) = (x2 - x1, y2 - y1);
+    let length_squared = dx * dx + dy * dy;
+    let color = [line.r, line.g, line.b].map(|channel| channel as f64 / 255.);
+    let alpha = line.a as f64 / 255.;
+    for y in top..bottom {
+      for x in left..right {
+        let (px, py) = (x as f64 + 0.5 - x1, y as f64 + 0.5 - y1);
+        // Distance from the centre of the pixel to the closest point of the line.
+        let t = if length_squared == 0. {
+          0.
+        } else {
+          ((px * dx + py * dy) / length_squared).clamp(0., 1.)
+        };
+        let distance = (px - t * dx).hypot(py - t * dy);
+        let coverage = (radius + 0.5 - distance).clamp(0., 1.);
+        if coverage == 0. {
+          continue;
+        }
+        let index = ((y * width + x) * 4) as usize;
+        blend(&mut self.pixels[index..index + 4], color, alpha * coverage);
+      }
+    }
+  }
+
+  fn stamp(&mut self, stamp: &Stamp, texture: &Texture) {
+    let effect_pixels;
+    let pixels = if stamp.effects.is_empty() {
+      &texture.pixels
+    } else {
+      effect_pixels = apply_effects(texture, &stamp.effects);
+      &effect_pixels
+    };
+    // Every pixel of the stage the costume can cover, whatever its direction.
+    let scale = stamp.size / 100. / texture.bitmap_resolution as f64;
+    let radius = (texture.width as f64).hypot(texture.height as f64) * scale / 2.;
+    let (center_x, center_y) = (
+      stamp.x + self.width as f64 / 2.,
+      self.height as f64 / 2. - stamp.y,
+    );
+    let clip = |value: f64, max: u32| (value.max(0.) as u32).min(max);
+    let left = clip(center_x - radius, self.width);
+    let right = clip((center_x + radius).ceil(), self.width);
+    let top = clip(center_y - radius, self.height);
+    let bottom = clip((center_y + radius).ceil(), self.height);
+    for y in top..bottom {
+      for x in left..right {
+        let stage_x = x as f64 + 0.5 - self.width as f64 / 2.;
+        let stage_y = self.height as f64 / 2. - (y as f64 + 0.5);
+        let Some((u, v)) = stamp.texel(texture, stage_x, stage_y) else {
+          continue;
+        };
+        let texel = ((v * texture.width + u) * 4) as usize;
+        let color = [0, 1, 2].map(|channel| pixels[texel + channel] as f64 / 255.);
+        let alpha = pixels[texel + 3] as f64 / 255.;
+        if alpha == 0. {
+          continue;
+        }
+        let index = ((y * self.width + x) * 4) as usize;
+        blend(&mut self.pixels[index..index + 4], color, alpha);
+      }
+    }
+  }
+}
+
+/// Blends a colour with straight alpha over a pixel with straight alpha.
+fn blend(pixel: &mut [u8], color: [f64; 3], alpha: f64) {
+  let below = pixel[3] as f64 / 255. * (1. - alpha);
+  let result = alpha + below;
+  if result == 0. {
+    return;
+  }
+  for (channel, color) in pixel.iter_mut().zip(color) {
+    let below_color = *channel as f64 / 255.;
+    *channel = ((color * alpha + below_color * below) / result * 255.).round() as u8;
   }
+  pixel[3] = (result * 255.).round() as u8;
 }
@@ -1,35 +1,217 @@
-use std::collections::LinkedList;
-use std::thread::panicking;
-use std::{collections::HashMap, fs::remove_dir_all, process::Command};
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::mem::take;
+use std::sync::{Arc, Mutex};
+use std::{collections::HashMap, fs::read};
 
 use derivative::Derivative;
-use sdl2::{
-  pixels::Color,
-  render::Canvas,
-  render::{Texture as sdl2Texture, TextureCreator},
-  ttf::Font,
-  video::Window,
-  video::WindowContext,
-};
 
 use crate::block::Value;
-use crate::pen::{render_pen, PenInstruction};
-use crate::{json, target::Target};
+use crate::clock::Clock;
+use crate::json::{self, LoadError};
+use crate::monitor::Monitor;
+use crate::pen::{PenInstruction, PenLayer};
+use crate::random::Random;
+use crate::sound::{Mixer, NullSink, SoundInstruction};
+use crate::target::{
+  scratch_key, set_layer, update_pen, Target, TargetData, TargetState,
+};
+use crate::touching::{record_drawable, touches_point, Drawable};
+use zip::ZipArchive;
 
-#[derive(Debug)]
-pub struct Project<'a> {
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Project {
   pub config: Config,
-  pub target_name_to_target_index: HashMap<String, usize>,
-  pub targets: Vec<Target<'a>>,
-  pub textures: Vec<Texture<'a>>,
-  pub shared_state: SharedState,
+  pub(crate) targets: Vec<Target>,
+  pub(crate) shared_state: SharedState,
+  #[derivative(Debug = "ignore")]
+  pub(crate) say_listeners: Vec<SayListener>,
+  #[derivative(Debug = "ignore")]
+  pub(crate) clock: Box<dyn Clock>,
+  /// The draggable sprite the mouse button was pressed on, if it is still held.
+  pub(crate) drag: Option<Drag>,
+  pub(crate) mixer: Arc<Mutex<Mixer>>,
+  /// Consumes the sound while no audio device is connected.
+  pub(crate) null_sink: Option<NullSink>,
+}
+
+/// Scratch only starts dragging once the mouse has moved this far, in stage units.
+pub const DRAG_THRESHOLD: f64 = 3.;
+
+#[derive(Debug)]
+pub struct Drag {
+  /// `TargetState::id` of the dragged sprite or clone.
+  pub id: usize,
+  /// Sprite position minus mouse position when the button was pressed.
+  pub offset_x: f64,
+  pub offset_y: f64,
+  /// Mouse position when the button was pressed.
+  pub start_x: f64,
+  pub start_y: f64,
+  /// Whether the mouse has moved past `DRAG_THRESHOLD` yet.
+  pub dragging: bool,
+}
+
+pub type SayListener = Box<dyn FnMut(&SayEvent)>;
+
+/// Input that an embedder or the window sends to a running project.
+#[derive(Debug)]
+pub enum InputEvent {
+  /// Stops everything and starts the `event_whenflagclicked` scripts.
+  GreenFlag,
+  /// Stops every script and deletes every clone.
+  StopAll,
+  /// Starts the receivers of a broadcast, by name.
+  Broadcast(String),
+  /// A key was pressed, or repeated while held down. Keys are named like in Scratch:
+  /// "space", "left arrow", "enter", "a", "1" and so on.
+  KeyDown(String),
+  KeyUp(String),
+  /// The mouse moved, in window pixels from the top left corner of the stage.
+  MouseMove {
+    x: i32,
+    y: i32,
+  },
+  /// The mouse button was pressed, which clicks the topmost visible sprite under the
+  /// mouse, or the stage if there is none.
+  MouseDown {
+    x: i32,
+    y: i32,
+  },
+  MouseUp {
+    x: i32,
+    y: i32,
+  },
+}
+
+/// Sent to the `Project::on_say` listeners whenever a say or think block runs, or a
+/// timed bubble runs out. An empty message means the bubble was removed.
+#[derive(Debug, Clone)]
+pub struct SayEvent {
+  /// Name of the sprite, clones report the name of their original sprite.
+  pub target: String,
+  pub kind: SayKind,
+  pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SayKind {
+  Say,
+  Think,
 }
 
 #[derive(Debug)]
 pub struct SharedState {
+  /// Index in `Project::targets` of every target, by name.
+  pub target_name_to_target_index: HashMap<String, usize>,
   pub global_variables: Vec<Value>,
   pub global_lists: Vec<Vec<Value>>,
-  pub pen: LinkedList<PenInstruction>,
+  /// Costumes of every target, `TargetData::costumes` index into it.
+  pub textures: Vec<Texture>,
+  /// Pen lines and stamps to draw on `SharedState::pen_layer` at the end of the
+  /// frame.
+  pub pen: Vec<PenInstruction>,
+  pub pen_layer: PenLayer,
+  /// Sounds to start or stop on `Project::mixer` at the end of the frame.
+  pub sounds: Vec<SoundInstruction>,
+  /// Handles of the sounds still playing, updated at the start of every frame.
+  pub playing_sounds: HashSet<usize>,
+  /// The next free sound handle.
+  pub next_sound: usize,
+  /// Lowercased names of the broadcasts sent this frame.
+  pub broadcasts: Vec<String>,
+  /// Lowercased names of the broadcasts that still have receiver scripts running.
+  pub running_broadcasts: HashSet<String>,
+  pub clone_requests: Vec<CloneRequest>,
+  /// The next free `TargetState::id`.
+  pub next_id: usize,
+  /// `say` blocks executed this frame, handed to the listeners after the frame.
+  pub says: Vec<SayEvent>,
+  /// Scratch names of the keys held down.
+  pub pressed_keys: HashSet<String>,
+  /// Mouse position in stage coordinates, clamped to the stage.
+  pub mouse_x: f64,
+  pub mouse_y: f64,
+  pub mouse_down: bool,
+  pub random: Random,
+  /// Beats per minute of the Music extension, a property of the stage.
+  pub tempo: f64,
+  /// `TargetState::id`s from the bottom layer to the top one, the stage is always at
+  /// the bottom.
+  pub layers: Vec<usize>,
+  /// The stage's current costume, sprites switch it with the backdrop blocks. Copied
+  /// to the stage's `TargetState::current_costume` after every frame.
+  pub backdrop: usize,
+  /// Costume names of the stage.
+  pub backdrop_names: Vec<String>,
+  /// Variable and list monitors, in drawing order.
+  pub monitors: Vec<Monitor>,
+  /// How every sprite, clone and the stage looks, by `TargetState::id`, updated
+  /// after each of them runs its scripts.
+  pub drawables: HashMap<usize, Drawable>,
+  pub stage_width: f64,
+  pub stage_height: f64,
+  /// `Clock::now` at the start of this frame.
+  pub time: f64,
+  /// `SharedState::time` when `sensing_resettimer` last ran.
+  pub timer_start: f64,
+  /// `Clock::unix_time` at the start of this frame.
+  pub unix_time: f64,
+  /// `Clock::utc_offset` at the start of this frame.
+  pub utc_offset: f64,
+}
+
+impl SharedState {
+  /// Nothing loaded yet, on a stage `stage_width` by `stage_height` pixels.
+  pub fn new(stage_width: u32, stage_height: u32, random: Random) -> SharedState {
+    SharedState {
+      target_name_to_target_index: HashMap::new(),
+      global_variables: Vec::new(),
+      global_lists: Vec::new(),
+      textures: Vec::new(),
+      pen: Vec::new(),
+      pen_layer: PenLayer::new(stage_width, stage_height),
+      sounds: Vec::new(),
+      playing_sounds: HashSet::new(),
+      next_sound: 0,
+      broadcasts: Vec::new(),
+      running_broadcasts: HashSet::new(),
+      clone_requests: Vec::new(),
+      next_id: 0,
+      says: Vec::new(),
+      pressed_keys: HashSet::new(),
+      mouse_x: 0.,
+      mouse_y: 0.,
+      mouse_down: false,
+      random,
+      tempo: 60.,
+      layers: Vec::new(),
+      backdrop: 0,
+      backdrop_names: Vec::new(),
+      monitors: Vec::new(),
+      drawables: HashMap::new(),
+      stage_width: stage_width as f64,
+      stage_height: stage_height as f64,
+      time: 0.,
+      timer_start: 0.,
+      unix_time: 0.,
+      utc_offset: 0.,
+    }
+  }
+}
+
+/// Vanilla Scratch refuses to create clones past this limit.
+pub const MAX_CLONES: usize = 300;
+
+#[derive(Debug)]
+pub struct CloneRequest {
+  /// Name of the sprite to clone.
+  pub target: String,
+  /// The `TargetState::id` the clone goes behind, or the original sprite if `None`.
+  pub parent: Option<usize>,
+  /// The state to copy, or the original sprite's current state if `None`.
+  pub state: Option<TargetState>,
 }
 
 #[derive(Debug)]
@@ -37,59 +219,70 @@ pub struct Config {
   pub stage_width: u32,
   pub stage_height: u32,
   pub frame_rate: u32,
+  /// Seeds the random blocks, a different seed every run if `None`.
+  pub seed: Option<u64>,
 }
 
 #[derive(Derivative)]
 #[derivative(Debug)]
-pub struct Texture<'a> {
+pub struct Texture {
   pub bitmap_resolution: u32,
+  pub width: u32,
+  pub height: u32,
+  /// RGBA pixels, row by row.
   #[derivative(Debug = "ignore")]
-  pub texture: sdl2Texture<'a>,
+  pub pixels: Vec<u8>,
   pub rotation_center_x: f64,
   pub rotation_center_y: f64,
+  /// The smallest rectangle holding every pixel that is not fully transparent, as
+  /// (left, top, right, bottom) pixel edges, `None` if there is no such pixel.
+  pub opaque_bounds: Option<(u32, u32, u32, u32)>,
 }
 
-impl<'a> Project<'a> {
-  pub fn load(
-    path: &str,
-    texture_creator: &'a TextureCreator<WindowContext>,
-    config: Config,
-  ) -> Project<'a> {
-    Command::new("unzip")
-      .arg("-o")
-      .arg(path)
-      .arg("-d")
-      .arg("tmp")
-      .status()
-      .unwrap();
-    json::load(&texture_creator, config)
-  }
-  pub fn render(
-    &mut self,
-    canvas: &mut Canvas<Window>,
-    texture_creator: &'a TextureCreator<WindowContext>,
-    font: &Font,
-  ) {
-    canvas.set_draw_color(Color::WHITE);
-    canvas.clear();
-    render_pen(
-      self.config.stage_width,
-      self.config.stage_height,
-      canvas,
-      &self.shared_state.pen,
-    );
-    for target in &mut self.targets {
-      Target::render(
-        &target.data,
-        &mut target.state,
-        &self.textures,
-        canvas,
-        texture_creator,
-        font,
-        &self.config,
-      );
+impl Texture {
+  pub fn new(
+    bitmap_resolution: u32,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    rotation_center_x: f64,
+    rotation_center_y: f64,
+  ) -> Texture {
+    let mut opaque_bounds: Option<(u32, u32, u32, u32)> = None;
+    for (index, pixel) in pixels.chunks_exact(4).enumerate() {
+      if pixel[3] == 0 {
+        continue;
+      }
+      let (x, y) = (index as u32 % width, index as u32 / width);
+      opaque_bounds = Some(match opaque_bounds {
+        Some((left, top, right, bottom)) => {
+          (left.min(x), top.min(y), right.max(x + 1), bottom.max(y + 1))
+        }
+        None => (x, y, x + 1, y + 1),
+      });
+    }
+    Texture {
+      bitmap_resolution,
+      width,
+      height,
+      pixels,
+      rotation_center_x,
+      rotation_center_y,
+      opaque_bounds,
     }
   }
+}
+
+impl Project {
+  pub fn load(path: &str, config: Config) -> Result<Project, LoadError> {
+    Project::load_from_bytes(&read(path)?, config)
+  }
+
+  /// Loads a project from the contents of a .sb3 archive held in memory.
+  pub fn load_from_bytes(bytes: &[u8], config: Config) -> Result<Project, LoadError> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+    json::load(&mut archive, config)
+  }
 
   pub fn start_scripts(&mut self) {
     for target in &mut self.targets {
@@ -97,18 +290,387 @@ impl<'a> Project<'a> {
     }
   }
 
-  pub fn execute_scripts(&mut self) {
+  pub fn stop_scripts(&mut self) {
     for target in &mut self.targets {
-      target.execute_scripts(&mut self.shared_state);
+      target.stop_scripts();
     }
+    let ids: HashSet<usize> = self
+      .targets
+      .iter()
+      .flat_map(|target| target.states())
+      .map(|state| state.id)
+      .collect();
+    self.shared_state.layers.retain(|id| ids.contains(id));
+    self.shared_state.drawables.retain(|id, _| ids.contains(id));
+    self.shared_state.broadcasts.clear();
+    self.shared_state.running_broadcasts.clear();
+    self.shared_state.clone_requests.clear();
+    self.shared_state.sounds.clear();
+    self.shared_state.playing_sounds.clear();
+    self.mixer.lock().unwrap().run(SoundInstruction::StopAll);
   }
-}
 
-impl<'a> Drop for Project<'a> {
-  fn drop(&mut self) {
-    if panicking() {
+  pub fn send(&mut self, event: InputEvent) {
+    match event {
+      InputEvent::GreenFlag => {
+        self.shared_state.timer_start = self.shared_state.time;
+        self.stop_scripts();
+        self.start_scripts();
+      }
+      InputEvent::StopAll => self.stop_scripts(),
+      InputEvent::Broadcast(broadcast) => {
+        self.shared_state.broadcasts.push(broadcast.to_lowercase());
+        self.start_broadcast_scripts();
+      }
+      InputEvent::KeyDown(key) => {
+        let key = scratch_key(&Value::String(key));
+        for target in &mut self.targets {
+          target.start_key_scripts(&key);
+        }
+        self.shared_state.pressed_keys.insert(key);
+      }
+      InputEvent::KeyUp(key) => {
+        let key = scratch_key(&Value::String(key));
+        self.shared_state.pressed_keys.remove(&key);
+      }
+      InputEvent::MouseMove { x, y } => {
+        self.move_mouse(x, y);
+        self.drag();
+      }
+      InputEvent::MouseDown { x, y } => {
+        self.move_mouse(x, y);
+        if !self.shared_state.mouse_down {
+          self.shared_state.mouse_down = true;
+          self.mouse_down();
+        }
+      }
+      InputEvent::MouseUp { x, y } => {
+        self.move_mouse(x, y);
+        self.drag();
+        self.shared_state.mouse_down = false;
+        // A draggable sprite that was not dragged counts as clicked on release.
+        if let Some(drag) = self.drag.take() {
+          if !drag.dragging {
+            self.click(Some(drag.id));
+          }
+        }
+      }
+    }
+  }
+
+  /// Converts window pixels to stage coordinates, with the origin in the centre and
+  /// y pointing up, like `Renderer::render` does the other way round.
+  fn move_mouse(&mut self, x: i32, y: i32) {
+    let half_width = self.config.stage_width as f64 / 2.;
+    let half_height = self.config.stage_height as f64 / 2.;
+    self.shared_state.mouse_x = (x as f64 - half_width).clamp(-half_width, half_width);
+    self.shared_state.mouse_y =
+      (half_height - y as f64).clamp(-half_height, half_height);
+  }
+
+  /// Clicks non-draggable sprites and the stage right away, draggable sprites are
+  /// clicked or dragged once it is known whether the mouse moves.
+  fn mouse_down(&mut self) {
+    let (x, y) = (self.shared_state.mouse_x, self.shared_state.mouse_y);
+    let Some(state) = self.pick(x, y) else {
+      return self.click(None);
+    };
+    let id = state.id;
+    if state.draggable {
+      self.drag = Some(Drag {
+        id,
+        offset_x: state.x - x,
+        offset_y: state.y - y,
+        start_x: x,
+        start_y: y,
+        dragging: false,
+      });
+    } else {
+      self.click(Some(id));
+    }
+  }
+
+  /// Moves the dragged sprite to follow the mouse, keeping where it was grabbed.
+  fn drag(&mut self) {
+    let (x, y) = (self.shared_state.mouse_x, self.shared_state.mouse_y);
+    let Some(drag) = &mut self.drag else {
+      return;
+    };
+    if !drag.dragging {
+      if (x - drag.start_x).hypot(y - drag.start_y) < DRAG_THRESHOLD {
+        return;
+      }
+      drag.dragging = true;
+      let id = drag.id;
+      self.go_to_front(id);
+    }
+    let Some(drag) = &self.drag else {
       return;
+    };
+    let (id, offset_x, offset_y) = (drag.id, drag.offset_x, drag.offset_y);
+    match state_mut(&mut self.targets, id) {
+      Some(state) => {
+        state.x = x + offset_x;
+        state.y = y + offset_y;
+        if state.pen.is_down {
+          update_pen(&mut self.shared_state, state);
+        }
+      }
+      // The sprite was deleted while being dragged.
+      None => self.drag = None,
     }
-    remove_dir_all("tmp").unwrap();
   }
+
+  /// Draws the sprite or clone with the given `TargetState::id` above every other
+  /// sprite.
+  fn go_to_front(&mut self, id: usize) {
+    let layers = &mut self.shared_state.layers;
+    set_layer(layers, id, layers.len() as f64);
+  }
+
+  /// Starts the click scripts of the sprite or clone with the given `TargetState::id`,
+  /// or of the stage if `None`.
+  fn click(&mut self, id: Option<usize>) {
+    let target = self.targets.iter_mut().find(|target| match id {
+      Some(id) => target.states().any(|state| state.id == id),
+      None => target.data.is_stage,
+    });
+    if let Some(target) = target {
+      let id = id.unwrap_or(target.state.id);
+      target.start_click_scripts(id);
+    }
+  }
+
+  /// Every target and clone, from the bottom layer to the top one.
+  pub fn layered_states(&self) -> Vec<(&TargetData, &TargetState)> {
+    let mut states: HashMap<usize, (&TargetData, &TargetState)> = HashMap::new();
+    for target in &self.targets {
+      for state in target.states() {
+        states.insert(state.id, (&target.data, state));
+      }
+    }
+    self
+      .shared_state
+      .layers
+      .iter()
+      .filter_map(|id| states.get(id).copied())
+      .collect()
+  }
+
+  /// The topmost visible sprite or clone at the given stage coordinates.
+  fn pick(&self, x: f64, y: f64) -> Option<&TargetState> {
+    self
+      .layered_states()
+      .into_iter()
+      .rev()
+      .find(|(data, state)| {
+        !data.is_stage && touches_point(&self.shared_state, data, state, x, y)
+      })
+      .map(|(_, state)| state)
+  }
+
+  /// Replaces the clock the project reads its time from, which is a `FrameClock`
+  /// starting at the current date by default. Pass a `FrameClock` with a fixed
+  /// start for runs that report the same dates every time.
+  pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+    self.clock = Box::new(clock);
+  }
+
+  /// Calls `listener` for every `say` block executed from now on.
+  pub fn on_say<F: FnMut(&SayEvent) + 'static>(&mut self, listener: F) {
+    self.say_listeners.push(Box::new(listener));
+  }
+
+  /// The value of a variable, looked up by name in the target first and then among
+  /// the global variables.
+  pub fn variable(&self, target: &str, name: &str) -> Option<&Value> {
+    let target =
+      &self.targets[*self.shared_state.target_name_to_target_index.get(target)?];
+    match target.data.variable_name_to_index.get(name) {
+      Some(&index) if !target.data.is_stage => target.state.variables.get(index),
+      _ => self
+        .shared_state
+        .global_variables
+        .get(*self.stage()?.data.variable_name_to_index.get(name)?),
+    }
+  }
+
+  pub fn variable_mut(&mut self, target: &str, name: &str) -> Option<&mut Value> {
+    let target_index = *self.shared_state.target_name_to_target_index.get(target)?;
+    let target = &self.targets[target_index];
+    match target.data.variable_name_to_index.get(name) {
+      Some(&index) if !target.data.is_stage => {
+        self.targets[target_index].state.variables.get_mut(index)
+      }
+      _ => {
+        let index = *self.stage()?.data.variable_name_to_index.get(name)?;
+        self.shared_state.global_variables.get_mut(index)
+      }
+    }
+  }
+
+  /// The items of a list, looked up by name in the target first and then among the
+  /// global lists.
+  pub fn list(&self, target: &str, name: &str) -> Option<&Vec<Value>> {
+    let target =
+      &self.targets[*self.shared_state.target_name_to_target_index.get(target)?];
+    match target.data.list_name_to_index.get(name) {
+      Some(&index) if !target.data.is_stage => target.state.lists.get(index),
+      _ => self
+        .shared_state
+        .global_lists
+        .get(*self.stage()?.data.list_name_to_index.get(name)?),
+    }
+  }
+
+  pub fn list_mut(&mut self, target: &str, name: &str) -> Option<&mut Vec<Value>> {
+    let target_index = *self.shared_state.target_name_to_target_index.get(target)?;
+    let target = &self.targets[target_index];
+    match target.data.list_name_to_index.get(name) {
+      Some(&index) if !target.data.is_stage => {
+        self.targets[target_index].state.lists.get_mut(index)
+      }
+      _ => {
+        let index = *self.stage()?.data.list_name_to_index.get(name)?;
+        self.shared_state.global_lists.get_mut(index)
+      }
+    }
+  }
+
+  fn stage(&self) -> Option<&Target> {
+    self.targets.iter().find(|target| target.data.is_stage)
+  }
+
+  /// Whether any script, in any target or clone, has not finished yet.
+  pub fn is_running(&self) -> bool {
+    self.targets.iter().any(|target| {
+      !target.scripts.is_empty()
+        || target.clones.iter().any(|clone| !clone.scripts.is_empty())
+    })
+  }
+
+  /// Runs one frame.
+  pub fn execute_scripts(&mut self) {
+    self.shared_state.time = self.clock.now();
+    self.shared_state.unix_time = self.clock.unix_time();
+    self.shared_state.utc_offset = self.clock.utc_offset();
+    {
+      let mut mixer = self.mixer.lock().unwrap();
+      if let Some(sink) = &mut self.null_sink {
+        sink.advance(&mut mixer, self.shared_state.time);
+      }
+      self.shared_state.playing_sounds = mixer.playing().collect();
+    }
+    // Targets may have been dragged or changed by the embedder since the last frame.
+    for (index, target) in self.targets.iter().enumerate() {
+      for state in target.states() {
+        record_drawable(&mut self.shared_state, index, &target.data, state);
+      }
+    }
+    for index in 0..self.targets.len() {
+      self.targets[index].execute_scripts(&mut self.shared_state, index);
+      self.create_clones();
+    }
+    self.start_broadcast_scripts();
+    for instruction in take(&mut self.shared_state.pen) {
+      let shared = &mut self.shared_state;
+      shared.pen_layer.draw(&instruction, &shared.textures);
+    }
+    self.update_mixer();
+    let backdrop = self.shared_state.backdrop;
+    if let Some(stage) = self.targets.iter_mut().find(|target| target.data.is_stage) {
+      stage.state.current_costume = backdrop;
+    }
+    for say in take(&mut self.shared_state.says) {
+      for listener in &mut self.say_listeners {
+        listener(&say);
+      }
+    }
+  }
+
+  fn update_mixer(&mut self) {
+    let targets = self
+      .targets
+      .iter()
+      .flat_map(|target| target.states())
+      .map(|state| (state.id, (state.volume, state.sound_effects.clone())))
+      .collect();
+    let mut mixer = self.mixer.lock().unwrap();
+    mixer.set_targets(targets);
+    for instruction in take(&mut self.shared_state.sounds) {
+      mixer.run(instruction);
+    }
+  }
+
+  /// Hands the mixer to an audio device playing at `sample_rate`, which must call
+  /// `Mixer::mix` from then on. Until then sound goes to a `NullSink`.
+  pub fn connect_audio(&mut self, sample_rate: u32) -> Arc<Mutex<Mixer>> {
+    self.mixer.lock().unwrap().sample_rate = sample_rate;
+    self.null_sink = None;
+    self.mixer.clone()
+  }
+
+  fn create_clones(&mut self) {
+    let mut clone_count: usize =
+      self.targets.iter().map(|target| target.clones.len()).sum();
+    for request in take(&mut self.shared_state.clone_requests) {
+      if clone_count >= MAX_CLONES {
+        break;
+      }
+      let Some(&index) = self
+        .shared_state
+        .target_name_to_target_index
+        .get(&request.target)
+      else {
+        continue;
+      };
+      let target = &mut self.targets[index];
+      if target.data.is_stage {
+        continue;
+      }
+      let mut state = request.state.unwrap_or_else(|| target.state.clone_state());
+      let id = self.shared_state.next_id;
+      state.id = id;
+      self.shared_state.next_id += 1;
+      let parent = request.parent.unwrap_or(target.state.id);
+      target.add_clone(state);
+      let state = &target.clones.last().unwrap().state;
+      record_drawable(&mut self.shared_state, index, &target.data, state);
+      let layers = &mut self.shared_state.layers;
+      let index = layers.iter().position(|&layer| layer == parent);
+      layers.insert(index.unwrap_or(layers.len()), id);
+      clone_count += 1;
+    }
+  }
+
+  /// Starts the receivers of every broadcast sent during this frame, then records
+  /// which broadcasts are still being handled for `event_broadcastandwait`.
+  fn start_broadcast_scripts(&mut self) {
+    let mut broadcasts = take(&mut self.shared_state.broadcasts);
+    let mut seen = HashSet::with_capacity(broadcasts.len());
+    broadcasts.retain(|broadcast| seen.insert(broadcast.clone()));
+    for broadcast in &broadcasts {
+      for target in &mut self.targets {
+        target.start_broadcast_scripts(broadcast);
+      }
+    }
+    self.shared_state.running_broadcasts = self
+      .targets
+      .iter()
+      .flat_map(|target| target.running_broadcasts())
+      .collect();
+  }
+}
+
+fn state_mut(targets: &mut [Target], id: usize) -> Option<&mut TargetState> {
+  targets.iter_mut().find_map(|target| {
+    if target.state.id == id {
+      return Some(&mut target.state);
+    }
+    target
+      .clones
+      .iter_mut()
+      .find(|clone| clone.state.id == id)
+      .map(|clone| &mut clone.state)
+  })
 }
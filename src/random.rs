@@ -0,0 +1,91 @@
+use std::cell::Cell;
+
+/// The random numbers of every block that picks at random, an xorshift64*
+/// generator. Projects loaded with the same `Config::seed` make the same choices.
+///
+/// The state is in a `Cell` because reporters only get a `&SharedState`.
+#[derive(Debug)]
+pub struct Random {
+  state: Cell<u64>,
+}
+
+impl Random {
+  pub fn new(seed: u64) -> Random {
+    // Scrambled with splitmix64 so that similar seeds start far apart, and so that
+    // the state is never 0, which xorshift would never leave.
+    let mut seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+    seed = (seed ^ (seed >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    seed = (seed ^ (seed >> 27)).wrapping_mul(0x94d049bb133111eb);
+    seed ^= seed >> 31;
+    Random {
+      state: Cell::new(seed.max(1)),
+    }
+  }
+
+  fn next(&self) -> u64 {
+    let mut x = self.state.get();
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    self.state.set(x);
+    x.wrapping_mul(0x2545f4914f6cdd1d)
+  }
+
+  /// A number from 0 up to, but never, 1, like `Math.random`.
+  pub fn fraction(&self) -> f64 {
+    (self.next() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  /// An integer from 0 up to, but never, `count`, each as likely as the others.
+  pub fn below(&self, count: usize) -> usize {
+    let count = count as u64;
+    // Numbers past the last whole multiple of `count` would favour small results.
+    let limit = u64::MAX - u64::MAX % count;
+    loop {
+      let x = self.next();
+      if x < limit {
+        return (x % count) as usize;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn same_seed_same_numbers() {
+    let (a, b) = (Random::new(7), Random::new(7));
+    for _ in 0..100 {
+      assert_eq!(a.next(), b.next());
+    }
+    assert_ne!(Random::new(7).next(), Random::new(8).next());
+  }
+
+  #[test]
+  fn zero_seed() {
+    let random = Random::new(0);
+    assert_ne!(random.next(), random.next());
+  }
+
+  #[test]
+  fn fraction_range() {
+    let random = Random::new(1);
+    for _ in 0..10000 {
+      let fraction = random.fraction();
+      assert!((0. ..1.).contains(&fraction));
+    }
+  }
+
+  #[test]
+  fn below_covers_range() {
+    let random = Random::new(2);
+    let mut seen = [0; 3];
+    for _ in 0..3000 {
+      seen[random.below(3)] += 1;
+    }
+    assert!(seen.iter().all(|&count| count > 800));
+    assert_eq!(random.below(1), 0);
+  }
+}
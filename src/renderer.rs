@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::mem::take;
+
+use sdl2::{
+  pixels::{Color, PixelFormatEnum},
+  rect::Rect,
+  render::{BlendMode, Canvas, Texture as sdl2Texture, TextureCreator},
+  ttf::Font,
+  video::{Window, WindowContext},
+};
+
+use crate::{
+  block::Value,
+  bubble::{bubble_pixels, bubble_size, wrap_text, LINE_HEIGHT, PADDING, STROKE_WIDTH},
+  effects::{apply_effects, Effects},
+  monitor::{Monitor, MonitorMode},
+  pen::PenLayer,
+  project::{Project, SayKind},
+  target::{RotationStyle, Say, TargetData, TargetState},
+};
+
+/// Colour of the text in bubbles and monitors, from scratch-render and scratch-gui.
+const TEXT_COLOR: Color = Color::RGB(0x57, 0x5e, 0x75);
+const MONITOR_BACKGROUND: Color = Color::RGB(0xe5, 0xf0, 0xff);
+const MONITOR_BORDER: Color = Color::RGBA(0, 0, 0, 38);
+const VARIABLE_COLOR: Color = Color::RGB(0xff, 0x8c, 0x1a);
+const LIST_COLOR: Color = Color::RGB(0xfc, 0x66, 0x2c);
+/// Size of list monitors saved with a size of 0.
+const LIST_WIDTH: u32 = 100;
+const LIST_HEIGHT: u32 = 200;
+
+/// Draws a `Project` to an SDL window. The project itself only holds pixels in
+/// memory, so it can run without a video subsystem.
+pub struct Renderer<'a> {
+  texture_creator: &'a TextureCreator<WindowContext>,
+  /// Uploaded copies of `SharedState::textures`, by the same index.
+  textures: Vec<sdl2Texture<'a>>,
+  /// Speech and thought bubbles, by `TargetState::id`.
+  bubbles: HashMap<usize, Bubble<'a>>,
+  /// Costumes with graphic effects applied, by `TargetState::id`, along with the
+  /// `SharedState::textures` index and the effects they were made from.
+  effects: HashMap<usize, (usize, Effects, sdl2Texture<'a>)>,
+  used_effects: HashMap<usize, (usize, Effects, sdl2Texture<'a>)>,
+  /// Rendered text by content and colour, kept while it is drawn every frame.
+  texts: HashMap<(String, (u8, u8, u8)), sdl2Texture<'a>>,
+  used_texts: HashMap<(String, (u8, u8, u8)), sdl2Texture<'a>>,
+  /// How far each list monitor is scrolled, in pixels, by index in
+  /// `SharedState::monitors`.
+  scrolls: HashMap<usize, i32>,
+  /// Where the list monitors were drawn in the last frame, for `Renderer::scroll`.
+  list_rects: Vec<(usize, Rect)>,
+  /// The uploaded `SharedState::pen_layer` and the `PenLayer::version` it was
+  /// uploaded at.
+  pen: Option<(usize, sdl2Texture<'a>)>,
+}
+
+impl<'a> Renderer<'a> {
+  pub fn new(texture_creator: &'a TextureCreator<WindowContext>) -> Renderer<'a> {
+    Renderer {
+      texture_creator,
+      textures: Vec::new(),
+      bubbles: HashMap::new(),
+      effects: HashMap::new(),
+      used_effects: HashMap::new(),
+      texts: HashMap::new(),
+      used_texts: HashMap::new(),
+      scrolls: HashMap::new(),
+      list_rects: Vec::new(),
+      pen: None,
+    }
+  }
+
+  /// Scrolls the list monitor under the given window coordinates by `amount`
+  /// pixels, downwards if positive.
+  pub fn scroll(&mut self, x: i32, y: i32, amount: i32) {
+    let list = self
+      .list_rects
+      .iter()
+      .rev()
+      .find(|(_, rect)| rect.contains_point((x, y)));
+    if let Some(&(index, _)) = list {
+      *self.scrolls.entry(index).or_insert(0) += amount;
+    }
+  }
+
+  pub fn render(
+    &mut self,
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    project: &Project,
+  ) {
+    for texture in &project.shared_state.textures[self.textures.len()..] {
+      self
+        .textures
+        .push(self.upload(texture.width, texture.height, &texture.pixels));
+    }
+    canvas.set_draw_color(Color::WHITE);
+    canvas.clear();
+    let mut bubbles = HashMap::with_capacity(self.bubbles.len());
+    for (data, state) in project.layered_states() {
+      self.render_target(canvas, font, project, data, state, &mut bubbles);
+      if data.is_stage {
+        self.render_pen(canvas, &project.shared_state.pen_layer);
+      }
+    }
+    self.bubbles = bubbles;
+    self.effects = take(&mut self.used_effects);
+    self.render_monitors(canvas, font, project);
+    self.texts = take(&mut self.used_texts);
+  }
+
+  /// Draws the pen layer, uploading it again only if it changed.
+  fn render_pen(&mut self, canvas: &mut Canvas<Window>, pen: &PenLayer) {
+    let texture = match self.pen.take() {
+      Some((version, texture)) if version == pen.version => texture,
+      _ => self.upload(pen.width, pen.height, &pen.pixels),
+    };
+    canvas.copy(&texture, None, None).unwrap();
+    self.pen = Some((pen.version, texture));
+  }
+
+  fn upload(&self, width: u32, height: u32, pixels: &[u8]) -> sdl2Texture<'a> {
+    let mut uploaded = self
+      .texture_creator
+      .create_texture_static(PixelFormatEnum::RGBA32, width, height)
+      .unwrap();
+    uploaded.update(None, pixels, width as usize * 4).unwrap();
+    uploaded.set_blend_mode(BlendMode::Blend);
+    uploaded
+  }
+
+  fn render_target(
+    &mut self,
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    project: &Project,
+    data: &TargetData,
+    state: &TargetState,
+    bubbles: &mut HashMap<usize, Bubble<'a>>,
+  ) {
+    if !state.visible {
+      return;
+    }
+    let texture_index = data.costume_index_to_texture_index[&state.current_costume];
+    let mut effect_texture = None;
+    if !state.effects.is_empty() {
+      let texture = match self.effects.remove(&state.id) {
+        Some((index, effects, texture))
+          if index == texture_index && effects == state.effects =>
+        {
+          texture
+        }
+        _ => {
+          let texture = &project.shared_state.textures[texture_index];
+          let pixels = apply_effects(texture, &state.effects);
+          self.upload(texture.width, texture.height, &pixels)
+        }
+      };
+      effect_texture = Some(texture);
+    }
+    let texture = effect_texture
+      .as_ref()
+      .unwrap_or(&self.textures[texture_index]);
+    let config = &project.config;
+    let scale = state.size
+      / project.shared_state.textures[texture_index].bitmap_resolution as f64;
+    let query = texture.query();
+    let width = query.width as f64 * scale / 100.;
+    let height = query.height as f64 * scale / 100.;
+    let x = config.stage_width as i32 / 2 + state.x as i32 - width as i32 / 2;
+    let y = (config.stage_height as i32 / 2 - state.y as i32) - height as i32 / 2;
+    let angle: f64;
+    let flip: bool;
+    match state.rotation_style {
+      RotationStyle::AllAround => {
+        angle = state.direction - 90.;
+        flip = false;
+      }
+      RotationStyle::DontRotate => {
+        angle = 0.;
+        flip = false;
+      }
+      RotationStyle::LeftRight => {
+        angle = 0.;
+        flip = state.direction < 0.;
+      }
+    }
+    canvas
+      .copy_ex(
+        texture,
+        None,
+        Rect::new(x, y, width as u32, height as u32),
+        angle,
+        None,
+        flip,
+        false,
+      )
+      .unwrap();
+    if let Some(texture) = effect_texture {
+      let effects = (texture_index, state.effects.clone(), texture);
+      self.used_effects.insert(state.id, effects);
+    }
+    if let Some(say) = &state.say {
+      // Bounds of the rotated costume, in window pixels.
+      let radians = angle.to_radians();
+      let half_width =
+        (width * radians.cos().abs() + height * radians.sin().abs()) / 2.;
+      let half_height =
+        (width * radians.sin().abs() + height * radians.cos().abs()) / 2.;
+      let center_x = config.stage_width as f64 / 2. + state.x;
+      let center_y = config.stage_height as f64 / 2. - state.y;
+      let bounds = (
+        (center_x - half_width) as i32,
+        (center_x + half_width) as i32,
+        (center_y - half_height) as i32,
+      );
+      let mut bubble = match self.bubbles.remove(&state.id) {
+        Some(bubble) if bubble.kind == say.kind && bubble.message == say.message => {
+          bubble
+        }
+        previous => {
+          let on_right = previous.is_none_or(|bubble| bubble.on_right);
+          self.create_bubble(font, say, on_right)
+        }
+      };
+      self.render_bubble(canvas, project, &mut bubble, bounds);
+      bubbles.insert(state.id, bubble);
+    }
+  }
+
+  fn create_bubble(&self, font: &Font, say: &Say, on_right: bool) -> Bubble<'a> {
+    let lines: Vec<(sdl2Texture<'a>, u32)> = wrap_text(font, &say.message)
+      .iter()
+      .filter(|line| !line.is_empty())
+      .map(|line| {
+        let surface = font.render(line).blended(TEXT_COLOR).unwrap();
+        let width = surface.width();
+        let texture = self
+          .texture_creator
+          .create_texture_from_surface(surface)
+          .unwrap();
+        (texture, width)
+      })
+      .collect();
+    let text_width = lines.iter().map(|(_, width)| *width).max().unwrap_or(0);
+    let (width, height) = bubble_size(text_width, lines.len());
+    let pixels = bubble_pixels(say.kind, text_width, lines.len());
+    Bubble {
+      kind: say.kind,
+      message: say.message.clone(),
+      on_right,
+      width,
+      height,
+      shape: self.upload(width, height, &pixels),
+      lines,
+    }
+  }
+
+  /// Draws a bubble above the sprite with the given left, right and top bounds. Like
+  /// in Scratch, the bubble stays on its side of the sprite unless it would go off
+  /// the stage and fits on the other side.
+  fn render_bubble(
+    &self,
+    canvas: &mut Canvas<Window>,
+    project: &Project,
+    bubble: &mut Bubble<'a>,
+    (left, right, top): (i32, i32, i32),
+  ) {
+    let stage_width = project.config.stage_width as i32;
+    let (width, height) = (bubble.width as i32, bubble.height as i32);
+    if bubble.on_right && right + width > stage_width && left - width > 0 {
+      bubble.on_right = false;
+    } else if !bubble.on_right && left - width < 0 && right + width < stage_width {
+      bubble.on_right = true;
+    }
+    let x = if bubble.on_right {
+      right.min(stage_width - width).max(0)
+    } else {
+      (left - width).max(0).min(stage_width - width)
+    };
+    let y = (top - height).max(0);
+    // The tail is drawn on the right, it has to point back at the sprite.
+    canvas
+      .copy_ex(
+        &bubble.shape,
+        None,
+        Rect::new(x, y, bubble.width, bubble.height),
+        0.,
+        None,
+        bubble.on_right,
+        false,
+      )
+      .unwrap();
+    let padded_width = (bubble.width - STROKE_WIDTH) as i32;
+    let mut line_y = y + (STROKE_WIDTH / 2 + PADDING) as i32;
+    for (texture, line_width) in &bubble.lines {
+      let query = texture.query();
+      let line_x =
+        x + (STROKE_WIDTH / 2) as i32 + (padded_width - *line_width as i32) / 2;
+      canvas
+        .copy(
+          texture,
+          None,
+          Rect::new(line_x, line_y, query.width, query.height),
+        )
+        .unwrap();
+      line_y += LINE_HEIGHT as i32;
+    }
+  }
+
+  fn render_monitors(
+    &mut self,
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    project: &Project,
+  ) {
+    canvas.set_blend_mode(BlendMode::Blend);
+    self.list_rects.clear();
+    let Some(stage) = project.targets.iter().find(|target| target.data.is_stage) else {
+      return;
+    };
+    for (index, monitor) in project.shared_state.monitors.iter().enumerate() {
+      if !monitor.visible {
+        continue;
+      }
+      let target = monitor.target.as_deref().unwrap_or(&stage.data.name);
+      let label = match &monitor.target {
+        Some(target) => format!("{target}: {}", monitor.name),
+        None => monitor.name.clone(),
+      };
+      if monitor.mode == MonitorMode::List {
+        let empty = Vec::new();
+        let items = project.list(target, &monitor.name).unwrap_or(&empty);
+        self.render_list_monitor(canvas, font, index, monitor, &label, items);
+      } else {
+        let value = project
+          .variable(target, &monitor.name)
+          .map(monitor_text)
+          .unwrap_or_default();
+        self.render_variable_monitor(canvas, font, monitor, &label, &value);
+      }
+    }
+  }
+
+  fn render_variable_monitor(
+    &mut self,
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    monitor: &Monitor,
+    label: &str,
+    value: &str,
+  ) {
+    let (x, y) = (monitor.x as i32, monitor.y as i32);
+    let text_height = font.height() as u32;
+    let value_width = text_size(font, value).0 + 10;
+    if monitor.mode == MonitorMode::Large {
+      let width = value_width.max(48);
+      let rect = Rect::new(x, y, width, text_height + 6);
+      fill_rounded_rect(canvas, rect, 4, MONITOR_BORDER);
+      fill_rounded_rect(canvas, inset(rect, 1), 3, VARIABLE_COLOR);
+      let value_x = x + (width - value_width) as i32 / 2 + 5;
+      self.draw_text(canvas, font, value, Color::WHITE, value_x, y + 3);
+      return;
+    }
+    let label_width = text_size(font, label).0;
+    let value_width = value_width.max(40);
+    let width = 5 + label_width + 5 + value_width + 5;
+    let row_height = text_height + 2;
+    let mut height = row_height + 8;
+    if let MonitorMode::Slider { .. } = monitor.mode {
+      height += 16;
+    }
+    let rect = Rect::new(x, y, width, height);
+    fill_rounded_rect(canvas, rect, 4, MONITOR_BORDER);
+    fill_rounded_rect(canvas, inset(rect, 1), 3, MONITOR_BACKGROUND);
+    self.draw_text(canvas, font, label, TEXT_COLOR, x + 5, y + 5);
+    let value_x = x + 5 + label_width as i32 + 5;
+    let value_rect = Rect::new(value_x, y + 4, value_width, row_height);
+    fill_rounded_rect(canvas, value_rect, 4, VARIABLE_COLOR);
+    let text_x = value_x + (value_width - text_size(font, value).0) as i32 / 2;
+    self.draw_text(canvas, font, value, Color::WHITE, text_x, y + 5);
+    if let MonitorMode::Slider { min, max, .. } = monitor.mode {
+      let track = Rect::new(x + 6, y + height as i32 - 12, width - 12, 4);
+      fill_rounded_rect(canvas, track, 2, MONITOR_BORDER);
+      let value = value.parse::<f64>().unwrap_or(min);
+      let fraction = if max > min {
+        ((value - min) / (max - min)).clamp(0., 1.)
+      } else {
+        0.
+      };
+      let knob_x = track.x() + (fraction * track.width() as f64) as i32 - 6;
+      let knob = Rect::new(knob_x, track.y() - 4, 12, 12);
+      fill_rounded_rect(canvas, knob, 6, MONITOR_BORDER);
+      fill_rounded_rect(canvas, inset(knob, 1), 5, Color::WHITE);
+    }
+  }
+
+  fn render_list_monitor(
+    &mut self,
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    index: usize,
+    monitor: &Monitor,
+    label: &str,
+    items: &[Value],
+  ) {
+    let width = if monitor.width > 0. {
+      monitor.width as u32
+    } else {
+      LIST_WIDTH
+    };
+    let height = if monitor.height > 0. {
+      monitor.height as u32
+    } else {
+      LIST_HEIGHT
+    };
+    let rect = Rect::new(monitor.x as i32, monitor.y as i32, width, height);
+    let (x, y) = (rect.x(), rect.y());
+    let text_height = font.height() as u32;
+    let bar_height = text_height + 6;
+    fill_rounded_rect(canvas, rect, 4, MONITOR_BORDER);
+    fill_rounded_rect(canvas, inset(rect, 1), 3, Color::WHITE);
+    let label_x = x + (width as i32 - text_size(font, label).0 as i32) / 2;
+    self.draw_text(canvas, font, label, TEXT_COLOR, label_x, y + 3);
+    let length = format!("length {}", items.len());
+    let length_x = x + (width as i32 - text_size(font, &length).0 as i32) / 2;
+    let length_y = y + height.saturating_sub(bar_height) as i32 + 3;
+    self.draw_text(canvas, font, &length, TEXT_COLOR, length_x, length_y);
+    let body = Rect::new(
+      x + 1,
+      y + bar_height as i32,
+      width.saturating_sub(2),
+      height.saturating_sub(2 * bar_height),
+    );
+    canvas.set_draw_color(MONITOR_BACKGROUND);
+    canvas.fill_rect(body).unwrap();
+    self.list_rects.push((index, body));
+    if items.is_empty() {
+      let empty_x = x + (width as i32 - text_size(font, "(empty)").0 as i32) / 2;
+      let empty_y = body.y() + body.height().saturating_sub(text_height) as i32 / 2;
+      self.draw_text(canvas, font, "(empty)", TEXT_COLOR, empty_x, empty_y);
+      return;
+    }
+    let row_height = (text_height + 6) as i32;
+    let max_scroll = (items.len() as i32 * row_height - body.height() as i32).max(0);
+    let scroll = self.scrolls.entry(index).or_insert(0);
+    *scroll = (*scroll).clamp(0, max_scroll);
+    let scroll = *scroll;
+    let first = (scroll / row_height) as usize;
+    let index_width = text_size(font, &items.len().to_string()).0 as i32;
+    canvas.set_clip_rect(body);
+    for (row, item) in items.iter().enumerate().skip(first) {
+      let row_y = body.y() + row as i32 * row_height - scroll;
+      if row_y > body.bottom() {
+        break;
+      }
+      let number = (row + 1).to_string();
+      self.draw_text(canvas, font, &number, TEXT_COLOR, body.x() + 3, row_y + 4);
+      let item_x = body.x() + 3 + index_width + 3;
+      let item_width = (body.right() - 3 - item_x).max(1) as u32;
+      let item_rect = Rect::new(item_x, row_y + 2, item_width, row_height as u32 - 2);
+      fill_rounded_rect(canvas, item_rect, 4, LIST_COLOR);
+      let text = monitor_text(item);
+      canvas.set_clip_rect(inset(item_rect, 2).intersection(body));
+      self.draw_text(canvas, font, &text, Color::WHITE, item_x + 3, row_y + 4);
+      canvas.set_clip_rect(body);
+    }
+    canvas.set_clip_rect(None);
+  }
+
+  /// Draws a line of text with its top left corner at the given position.
+  fn draw_text(
+    &mut self,
+    canvas: &mut Canvas<Window>,
+    font: &Font,
+    text: &str,
+    color: Color,
+    x: i32,
+    y: i32,
+  ) {
+    if text.is_empty() {
+      return;
+    }
+    let key = (text.to_string(), color.rgb());
+    let texture = match self
+      .used_texts
+      .remove(&key)
+      .or_else(|| self.texts.remove(&key))
+    {
+      Some(texture) => texture,
+      None => self
+        .texture_creator
+        .create_texture_from_surface(font.render(text).blended(color).unwrap())
+        .unwrap(),
+    };
+    let query = texture.query();
+    canvas
+      .copy(&texture, None, Rect::new(x, y, query.width, query.height))
+      .unwrap();
+    self.used_texts.insert(key, texture);
+  }
+}
+
+/// A speech or thought bubble, rendered once per message.
+struct Bubble<'a> {
+  kind: SayKind,
+  message: String,
+  /// Whether the bubble is on the right of its sprite.
+  on_right: bool,
+  width: u32,
+  height: u32,
+  /// The bubble without its text, with the tail on the right.
+  shape: sdl2Texture<'a>,
+  /// One texture per line of text, with its width.
+  lines: Vec<(sdl2Texture<'a>, u32)>,
+}
+
+/// How monitors show a value: numbers are rounded to six decimals.
+fn monitor_text(value: &Value) -> String {
+  match value {
+    Value::Float(number) if number.abs() > 0.01 => {
+      Value::Float((number * 1e6).round() / 1e6).to_string()
+    }
+    value => value.to_string(),
+  }
+}
+
+fn text_size(font: &Font, text: &str) -> (u32, u32) {
+  font.size_of(text).unwrap_or((0, 0))
+}
+
+fn inset(rect: Rect, amount: i32) -> Rect {
+  Rect::new(
+    rect.x() + amount,
+    rect.y() + amount,
+    (rect.width() as i32 - 2 * amount).max(1) as u32,
+    (rect.height() as i32 - 2 * amount).max(1) as u32,
+  )
+}
+
+/// Fills a rectangle with rounded corners, one row at a time.
+fn fill_rounded_rect(
+  canvas: &mut Canvas<Window>,
+  rect: Rect,
+  radius: i32,
+  color: Color,
+) {
+  canvas.set_draw_color(color);
+  let height = rect.height() as i32;
+  let radius = radius.min(rect.width() as i32 / 2).min(height / 2);
+  for row in 0..height {
+    let distance = if row < radius {
+      radius - row
+    } else if row >= height - radius {
+      row - (height - radius) + 1
+    } else {
+      0
+    };
+    let distance = distance as f64 - 0.5;
+    let inset = if distance > 0. {
+      (radius as f64 - (radius as f64 * radius as f64 - distance * distance).sqrt())
+        .round() as i32
+    } else {
+      0
+    };
+    let width = rect.width() as i32 - 2 * inset;
+    if width > 0 {
+      canvas
+        .fill_rect(Rect::new(rect.x() + inset, rect.y() + row, width as u32, 1))
+        .unwrap();
+    }
+  }
+}
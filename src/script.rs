@@ -2,11 +2,27 @@ use crate::block::Value;
 
 #[derive(Debug)]
 pub struct Script {
+  pub hat: usize,
   pub id: usize,
   pub stack: Vec<StackFrame>,
   pub arguments: Vec<Value>,
   pub arguments_start: usize,
   pub refresh: bool,
+  pub wait: Option<Wait>,
+}
+
+impl Script {
+  pub fn new(hat: usize) -> Script {
+    Script {
+      hat,
+      id: hat,
+      stack: vec![],
+      arguments: vec![],
+      arguments_start: 0,
+      refresh: true,
+      wait: None,
+    }
+  }
 }
 
 #[derive(Debug)]
@@ -24,3 +40,23 @@ pub enum StackFrame {
     old_arguments_start: usize,
   },
 }
+
+/// What a yielded script is waiting for before it executes its current block again.
+#[derive(Debug)]
+pub enum Wait {
+  /// Scripts started by this broadcast (lowercased name) to finish.
+  Broadcast(String),
+  /// `SharedState::time` to reach.
+  Timer(f64),
+  /// The condition of the current block to become true.
+  Condition,
+  /// A sound started with `sound_playuntildone` to end, by its handle.
+  Sound(usize),
+  /// A glide from one position to another, started at `SharedState::time` `start`.
+  Glide {
+    start: f64,
+    duration: f64,
+    from: (f64, f64),
+    to: (f64, f64),
+  },
+}
@@ -0,0 +1,533 @@
+use std::collections::HashMap;
+use std::io::{Cursor, ErrorKind};
+#[cfg(feature = "sdl")]
+use std::sync::{Arc, Mutex};
+
+use derivative::Derivative;
+#[cfg(feature = "sdl")]
+use sdl2::audio::AudioCallback;
+use symphonia::core::{
+  audio::SampleBuffer, codecs::DecoderOptions, errors::Error, formats::FormatOptions,
+  io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+use crate::music::{note_frequency, CONCURRENCY_LIMIT, DRUMS, INSTRUMENTS};
+
+/// A decoded sound, mixed down to one channel.
+#[derive(Derivative, Default)]
+#[derivative(Debug)]
+pub struct Sound {
+  pub sample_rate: u32,
+  #[derivative(Debug = "ignore")]
+  pub samples: Vec<f32>,
+}
+
+/// Decodes a WAV (PCM or ADPCM) or MP3 file. `extension` is a hint for the format.
+pub fn decode_sound(bytes: Vec<u8>, extension: &str) -> Result<Sound, String> {
+  let stream = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+  let mut hint = Hint::new();
+  hint.with_extension(extension);
+  let mut format = symphonia::default::get_probe()
+    .format(
+      &hint,
+      stream,
+      &FormatOptions::default(),
+      &MetadataOptions::default(),
+    )
+    .map_err(|error| error.to_string())?
+    .format;
+  let track = format.default_track().ok_or("no audio track")?;
+  let track_id = track.id;
+  let mut sample_rate = track.codec_params.sample_rate.unwrap_or(0);
+  let mut decoder = symphonia::default::get_codecs()
+    .make(&track.codec_params, &DecoderOptions::default())
+    .map_err(|error| error.to_string())?;
+  let mut samples = Vec::new();
+  loop {
+    let packet = match format.next_packet() {
+      Ok(packet) => packet,
+      Err(Error::IoError(error)) if error.kind() == ErrorKind::UnexpectedEof => break,
+      Err(error) => return Err(error.to_string()),
+    };
+    if packet.track_id() != track_id {
+      continue;
+    }
+    let decoded = match decoder.decode(&packet) {
+      Ok(decoded) => decoded,
+      // A damaged packet is skipped, like browsers do.
+      Err(Error::DecodeError(_)) => continue,
+      Err(error) => return Err(error.to_string()),
+    };
+    let spec = *decoded.spec();
+    sample_rate = spec.rate;
+    let channels = spec.channels.count();
+    let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+    buffer.copy_interleaved_ref(decoded);
+    for frame in buffer.samples().chunks(channels) {
+      samples.push(frame.iter().sum::<f32>() / channels as f32);
+    }
+  }
+  if sample_rate == 0 {
+    return Err(String::from("unknown sample rate"));
+  }
+  Ok(Sound {
+    sample_rate,
+    samples,
+  })
+}
+
+/// Sound effects of a target, in the units the sound blocks use.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SoundEffects {
+  pub pitch: f64,
+  pub pan: f64,
+}
+
+impl SoundEffects {
+  /// Looks up an effect by the name used in the `EFFECT` field, like "PITCH".
+  pub fn get_mut(&mut self, name: &str) -> Option<&mut f64> {
+    match name.to_lowercase().as_str() {
+      "pitch" => Some(&mut self.pitch),
+      "pan" => Some(&mut self.pan),
+      _ => None,
+    }
+  }
+
+  /// Keeps the effects in the ranges Scratch allows.
+  pub fn clamp(&mut self) {
+    self.pitch = self.pitch.clamp(-360., 360.);
+    self.pan = self.pan.clamp(-100., 100.);
+  }
+}
+
+/// Something to do with the mixer, queued in `SharedState::sounds` while scripts
+/// run.
+#[derive(Debug)]
+pub enum SoundInstruction {
+  /// Starts `sound`, an index in `Mixer::sounds`, for the sprite or clone with the
+  /// `TargetState::id` `target`, restarting it if that target is already playing
+  /// it.
+  Play {
+    handle: usize,
+    target: usize,
+    sound: usize,
+  },
+  /// Plays a MIDI note on one of `music::INSTRUMENTS` for `duration` seconds.
+  Note {
+    target: usize,
+    instrument: usize,
+    note: f64,
+    duration: f64,
+  },
+  /// Hits one of `music::DRUMS`.
+  Drum {
+    target: usize,
+    drum: usize,
+  },
+  StopAll,
+}
+
+#[derive(Debug)]
+enum Source {
+  Sound {
+    /// Identifies this playback in `SharedState::playing_sounds`.
+    handle: usize,
+    sound: usize,
+    /// Position in the sound, in its own samples.
+    position: f64,
+  },
+  Note {
+    instrument: usize,
+    frequency: f64,
+    duration: f64,
+  },
+  Drum(usize),
+}
+
+#[derive(Debug)]
+struct Voice {
+  target: usize,
+  source: Source,
+  /// Seconds played so far.
+  time: f64,
+}
+
+impl Voice {
+  /// The next sample, moving the sound forward by `rate` of its samples or the notes
+  /// forward by `step` seconds. `None` once the voice has finished.
+  fn next(&mut self, sounds: &[Sound], rate: f64, step: f64) -> Option<f32> {
+    let time = self.time;
+    self.time += step;
+    match &mut self.source {
+      Source::Sound {
+        sound, position, ..
+      } => {
+        let samples = &sounds[*sound].samples;
+        let index = *position as usize;
+        let next = samples.get(index + 1).copied().unwrap_or(0.);
+        let fraction = position.fract() as f32;
+        *position += rate;
+        Some(*samples.get(index)? * (1. - fraction) + next * fraction)
+      }
+      Source::Note {
+        instrument,
+        frequency,
+        duration,
+      } => INSTRUMENTS[*instrument].sample(*frequency, time, *duration),
+      Source::Drum(drum) => DRUMS[*drum].sample(time),
+    }
+  }
+}
+
+/// Mixes every sound being played into stereo samples for an audio device.
+#[derive(Debug)]
+pub struct Mixer {
+  /// Sample rate of the output.
+  pub sample_rate: u32,
+  pub sounds: Vec<Sound>,
+  voices: Vec<Voice>,
+  /// Volume from 0 to 100 and sound effects, by `TargetState::id`.
+  targets: HashMap<usize, (f64, SoundEffects)>,
+}
+
+impl Mixer {
+  pub fn new(sounds: Vec<Sound>) -> Mixer {
+    Mixer {
+      sample_rate: 44100,
+      sounds,
+      voices: Vec::new(),
+      targets: HashMap::new(),
+    }
+  }
+
+  pub fn run(&mut self, instruction: SoundInstruction) {
+    match instruction {
+      SoundInstruction::Play {
+        handle,
+        target,
+        sound,
+      } => {
+        self.voices.retain(|voice| {
+          !matches!(voice.source, Source::Sound { sound: playing, .. }
+            if voice.target == target && playing == sound)
+        });
+        self.add(
+          target,
+          Source::Sound {
+            handle,
+            sound,
+            position: 0.,
+          },
+        );
+      }
+      SoundInstruction::Note {
+        target,
+        instrument,
+        note,
+        duration,
+      } => {
+        if self.music_voices() < CONCURRENCY_LIMIT {
+          let source = Source::Note {
+            instrument,
+            frequency: note_frequency(note),
+            duration,
+          };
+          self.add(target, source);
+        }
+      }
+      SoundInstruction::Drum { target, drum } => {
+        if self.music_voices() < CONCURRENCY_LIMIT {
+          self.add(target, Source::Drum(drum));
+        }
+      }
+      SoundInstruction::StopAll => self.voices.clear(),
+    }
+  }
+
+  fn add(&mut self, target: usize, source: Source) {
+    self.voices.push(Voice {
+      target,
+      source,
+      time: 0.,
+    });
+  }
+
+  fn music_voices(&self) -> usize {
+    self
+      .voices
+      .iter()
+      .filter(|voice| !matches!(voice.source, Source::Sound { .. }))
+      .count()
+  }
+
+  /// Replaces the volume and effects of every target. Sounds of targets missing
+  /// from `targets`, like deleted clones, stop.
+  pub fn set_targets(&mut self, targets: HashMap<usize, (f64, SoundEffects)>) {
+    self.targets = targets;
+    let targets = &self.targets;
+    self
+      .voices
+      .retain(|voice| targets.contains_key(&voice.target));
+  }
+
+  /// Handles of the sounds still playing.
+  pub fn playing(&self) -> impl Iterator<Item = usize> + '_ {
+    self.voices.iter().filter_map(|voice| match voice.source {
+      Source::Sound { handle, .. } => Some(handle),
+      _ => None,
+    })
+  }
+
+  /// Fills `output` with interleaved stereo samples and moves every sound forward.
+  pub fn mix(&mut self, output: &mut [f32]) {
+    output.fill(0.);
+    let (sounds, targets) = (&self.sounds, &self.targets);
+    let sample_rate = self.sample_rate as f64;
+    self.voices.retain_mut(|voice| {
+      let Some((volume, effects)) = targets.get(&voice.target) else {
+        return false;
+      };
+      let gain = (volume / 100.) as f32;
+      let (mut rate, mut left, mut right) = (1., 1., 1.);
+      // Only sounds have effects, notes and drums only follow the volume.
+      if let Source::Sound { sound, .. } = voice.source {
+        // Ten units of pitch are a semitone.
+        rate = sounds[sound].sample_rate as f64 / sample_rate
+          * 2f64.powf(effects.pitch / 10. / 12.);
+        // Scratch leaves the sound alone when there is no pan, and pans it with
+        // equal power otherwise.
+        if effects.pan != 0. {
+          let angle = (effects.pan + 100.) / 200. * std::f64::consts::FRAC_PI_2;
+          (left, right) = (angle.cos() as f32, angle.sin() as f32);
+        }
+      }
+      for frame in output.chunks_mut(2) {
+        let Some(sample) = voice.next(sounds, rate, 1. / sample_rate) else {
+          return false;
+        };
+        frame[0] += gain * sample * left;
+        if let Some(second) = frame.get_mut(1) {
+          *second += gain * sample * right;
+        }
+      }
+      true
+    });
+    for sample in output {
+      *sample = sample.clamp(-1., 1.);
+    }
+  }
+}
+
+/// Stands in for an audio device when there is none, throwing away mixed sound as
+/// the project's clock advances so that sounds still end on time.
+#[derive(Debug, Default)]
+pub struct NullSink {
+  /// `Clock::now` when sound was last mixed.
+  time: Option<f64>,
+  buffer: Vec<f32>,
+}
+
+impl NullSink {
+  pub fn advance(&mut self, mixer: &mut Mixer, time: f64) {
+    let elapsed = time - self.time.unwrap_or(time);
+    self.time = Some(time);
+    let frames = (elapsed * mixer.sample_rate as f64).round().max(0.) as usize;
+    self.buffer.resize(frames * 2, 0.);
+    mixer.mix(&mut self.buffer);
+  }
+}
+
+/// Plays the sound of a `Mixer` through SDL, see `Project::connect_audio`.
+#[cfg(feature = "sdl")]
+pub struct AudioOutput(pub Arc<Mutex<Mixer>>);
+
+#[cfg(feature = "sdl")]
+impl AudioCallback for AudioOutput {
+  type Channel = f32;
+
+  fn callback(&mut self, output: &mut [f32]) {
+    self.0.lock().unwrap().mix(output);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A mixer at 44100Hz playing `sound` for target 0, with the given volume and
+  /// effects.
+  fn mixer(sound: Sound, volume: f64, effects: SoundEffects) -> Mixer {
+    let mut mixer = Mixer::new(vec![sound]);
+    mixer.set_targets(HashMap::from([(0, (volume, effects))]));
+    mixer.run(SoundInstruction::Play {
+      handle: 0,
+      target: 0,
+      sound: 0,
+    });
+    mixer
+  }
+
+  fn ramp(sample_rate: u32) -> Sound {
+    Sound {
+      sample_rate,
+      samples: (0..100).map(|sample| sample as f32 / 100.).collect(),
+    }
+  }
+
+  /// The left channel of the next `frames` frames.
+  fn left(mixer: &mut Mixer, frames: usize) -> Vec<f32> {
+    let mut output = vec![0.; frames * 2];
+    mixer.mix(&mut output);
+    output.iter().step_by(2).copied().collect()
+  }
+
+  fn assert_close(actual: &[f32], expected: &[f32]) {
+    assert_eq!(actual.len(), expected.len(), "{actual:?}");
+    for (actual_sample, expected_sample) in actual.iter().zip(expected) {
+      assert!(
+        (actual_sample - expected_sample).abs() < 1e-5,
+        "{actual:?} {expected:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn resampling_and_pitch() {
+    // Half the output rate, so every sample lasts two frames.
+    let mut slow = mixer(ramp(22050), 100., SoundEffects::default());
+    assert_close(&left(&mut slow, 4), &[0., 0.005, 0.01, 0.015]);
+    // An octave up plays it at the output rate.
+    let octave = SoundEffects {
+      pitch: 120.,
+      pan: 0.,
+    };
+    let mut fast = mixer(ramp(22050), 100., octave);
+    assert_close(&left(&mut fast, 4), &[0., 0.01, 0.02, 0.03]);
+    // A sound ends once all of its samples are played.
+    let mut short = mixer(ramp(44100), 100., SoundEffects::default());
+    left(&mut short, 99);
+    assert_eq!(short.playing().count(), 1);
+    left(&mut short, 2);
+    assert_eq!(short.playing().count(), 0);
+  }
+
+  #[test]
+  fn volume_and_pan_gains() {
+    let constant = || Sound {
+      sample_rate: 44100,
+      samples: vec![0.5; 10],
+    };
+    let frame = |volume, pan| {
+      let effects = SoundEffects { pitch: 0., pan };
+      let mut output = [0.; 2];
+      mixer(constant(), volume, effects).mix(&mut output);
+      output
+    };
+    assert_close(&frame(100., 0.), &[0.5, 0.5]);
+    assert_close(&frame(50., 0.), &[0.25, 0.25]);
+    assert_close(&frame(100., -100.), &[0.5, 0.]);
+    assert_close(&frame(100., 100.), &[0., 0.5]);
+    // Equal power in the middle of the sides.
+    let half = 0.5 * std::f32::consts::FRAC_1_SQRT_2;
+    assert_close(&frame(100., 1e-9), &[half, half]);
+  }
+
+  #[test]
+  fn restart_drops_old_handle() {
+    let mut mixer = mixer(ramp(44100), 100., SoundEffects::default());
+    mixer.set_targets(HashMap::from([
+      (0, (100., SoundEffects::default())),
+      (1, (100., SoundEffects::default())),
+    ]));
+    mixer.run(SoundInstruction::Play {
+      handle: 1,
+      target: 1,
+      sound: 0,
+    });
+    mixer.run(SoundInstruction::Play {
+      handle: 2,
+      target: 0,
+      sound: 0,
+    });
+    assert_eq!(mixer.playing().collect::<Vec<_>>(), [1, 2]);
+    mixer.run(SoundInstruction::StopAll);
+    assert_eq!(mixer.playing().count(), 0);
+  }
+
+  #[test]
+  fn set_targets_stops_deleted_clones() {
+    let mut mixer = mixer(ramp(44100), 100., SoundEffects::default());
+    mixer.set_targets(HashMap::from([
+      (0, (100., SoundEffects::default())),
+      (1, (100., SoundEffects::default())),
+    ]));
+    mixer.run(SoundInstruction::Play {
+      handle: 1,
+      target: 1,
+      sound: 0,
+    });
+    mixer.set_targets(HashMap::from([(0, (100., SoundEffects::default()))]));
+    assert_eq!(mixer.playing().collect::<Vec<_>>(), [0]);
+  }
+
+  /// A 16 bit PCM WAV file.
+  fn wav(sample_rate: u32, channels: u16, samples: &[i16]) -> Vec<u8> {
+    let data_size = samples.len() as u32 * 2;
+    let block_align = channels * 2;
+    let mut bytes = Vec::new();
+    bytes.extend(b"RIFF");
+    bytes.extend((36 + data_size).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(16u32.to_le_bytes());
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(channels.to_le_bytes());
+    bytes.extend(sample_rate.to_le_bytes());
+    bytes.extend((sample_rate * block_align as u32).to_le_bytes());
+    bytes.extend(block_align.to_le_bytes());
+    bytes.extend(16u16.to_le_bytes());
+    bytes.extend(b"data");
+    bytes.extend(data_size.to_le_bytes());
+    for sample in samples {
+      bytes.extend(sample.to_le_bytes());
+    }
+    bytes
+  }
+
+  #[test]
+  fn decode_wav() {
+    let stereo = wav(8000, 2, &[16384, 0, -16384, -16384, 0, -32768]);
+    let sound = decode_sound(stereo, "wav").unwrap();
+    assert_eq!(sound.sample_rate, 8000);
+    assert_close(&sound.samples, &[0.25, -0.5, -0.5]);
+    assert!(decode_sound(b"not a sound".to_vec(), "wav").is_err());
+  }
+
+  #[test]
+  fn finished_music_voices_leave_the_mixer() {
+    let mut mixer = Mixer::new(Vec::new());
+    mixer.set_targets(HashMap::from([(0, (100., SoundEffects::default()))]));
+    mixer.run(SoundInstruction::Drum { target: 0, drum: 0 });
+    mixer.run(SoundInstruction::Note {
+      target: 0,
+      instrument: 0,
+      note: 60.,
+      duration: 0.1,
+    });
+    assert_eq!(mixer.music_voices(), 2);
+    // A second, longer than the drum and the note above.
+    let mut output = vec![0.; 44100 * 2];
+    mixer.mix(&mut output);
+    assert!(output.iter().any(|&sample| sample != 0.));
+    assert_eq!(mixer.music_voices(), 0);
+  }
+
+  #[test]
+  fn concurrency_limit() {
+    let mut mixer = Mixer::new(Vec::new());
+    mixer.set_targets(HashMap::from([(0, (100., SoundEffects::default()))]));
+    for _ in 0..CONCURRENCY_LIMIT + 5 {
+      mixer.run(SoundInstruction::Drum { target: 0, drum: 0 });
+    }
+    assert_eq!(mixer.music_voices(), CONCURRENCY_LIMIT);
+  }
+}
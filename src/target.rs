@@ -1,23 +1,32 @@
 use crate::{
-  block::{Block, CustomBlock, Input, Value, VariableInput},
-  pen::PenInstruction,
-  project::{Config, SharedState, Texture},
-  script::{Script, StackFrame},
-};
-use derivative::Derivative;
-use sdl2::{
-  pixels::Color,
-  rect::Rect,
-  render::{Canvas, Texture as sdl2Texture, TextureCreator},
-  ttf::Font,
-  video::{Window, WindowContext},
+  block::{Block, CustomBlock, Input, ListInput, Value, VariableInput},
+  clock::{days_since_2000, DateTime},
+  effects::{hsv_to_rgb, rgb_to_hsv, Effects},
+  monitor::{Monitor, MonitorMode},
+  music::{
+    menu_index, DRUMS, INSTRUMENTS, MAX_BEATS, MAX_NOTE, MAX_TEMPO, MIN_NOTE, MIN_TEMPO,
+  },
+  pen::{PenInstruction, PenLine, Stamp, MAX_PEN_SIZE, MIN_PEN_SIZE},
+  project::{CloneRequest, SayEvent, SayKind, SharedState},
+  random::Random,
+  script::{Script, StackFrame, Wait},
+  sound::{SoundEffects, SoundInstruction},
+  touching::{record_drawable, touching_color, touching_object},
 };
 use std::{collections::HashMap, f64::consts::PI};
 
 #[derive(Debug)]
-pub struct Target<'a> {
+pub struct Target {
   pub data: TargetData,
-  pub state: TargetState<'a>,
+  pub state: TargetState,
+  pub scripts: Vec<Script>,
+  /// Clones share the `TargetData` of the original sprite.
+  pub clones: Vec<SpriteClone>,
+}
+
+#[derive(Debug)]
+pub struct SpriteClone {
+  pub state: TargetState,
   pub scripts: Vec<Script>,
 }
 
@@ -29,100 +38,510 @@ fn clamp_size(size: f64) -> f64 {
   }
 }
 
-impl<'a> Target<'a> {
-  pub fn render(
-    data: &TargetData,
-    state: &mut TargetState<'a>,
-    textures: &Vec<Texture>,
-    canvas: &mut Canvas<Window>,
-    texture_creator: &'a TextureCreator<WindowContext>,
-    font: &Font,
-    config: &Config,
-  ) {
-    if !state.visible {
+impl Target {
+  /// Stops every script, deletes every clone and clears the graphic effects and the
+  /// bubble.
+  pub fn stop_scripts(&mut self) {
+    self.scripts.clear();
+    self.clones.clear();
+    self.state.effects = Effects::default();
+    self.state.sound_effects = SoundEffects::default();
+    self.state.say = None;
+  }
+
+  pub fn start_scripts(&mut self) {
+    start_hats(
+      &self.data,
+      &mut self.scripts,
+      "event_whenflagclicked",
+      |_| true,
+      true,
+    );
+  }
+
+  /// Starts every script under a hat block `broadcast` (lowercased) triggers, in the
+  /// original sprite and in all of its clones.
+  pub fn start_broadcast_scripts(&mut self, broadcast: &str) {
+    let filter = |block: &Block| broadcast_name(block).as_deref() == Some(broadcast);
+    let opcode = "event_whenbroadcastreceived";
+    start_hats(&self.data, &mut self.scripts, opcode, filter, true);
+    for clone in &mut self.clones {
+      start_hats(&self.data, &mut clone.scripts, opcode, filter, true);
+    }
+  }
+
+  /// Starts every `event_whenkeypressed` script for `key` (a Scratch key name), in the
+  /// original sprite and in all of its clones. Scripts that are already running are
+  /// left alone.
+  pub fn start_key_scripts(&mut self, key: &str) {
+    let filter = |block: &Block| {
+      aux_field(block, "KEY_OPTION", |option| {
+        let option = scratch_key(&Value::String(option.to_string()));
+        option == "any" || option == key
+      })
+    };
+    let opcode = "event_whenkeypressed";
+    start_hats(&self.data, &mut self.scripts, opcode, filter, false);
+    for clone in &mut self.clones {
+      start_hats(&self.data, &mut clone.scripts, opcode, filter, false);
+    }
+  }
+
+  /// The states of the original sprite and of every clone.
+  pub fn states(&self) -> impl Iterator<Item = &TargetState> {
+    [&self.state]
+      .into_iter()
+      .chain(self.clones.iter().map(|clone| &clone.state))
+  }
+
+  /// Starts the `event_whenthisspriteclicked` scripts of the original sprite or clone
+  /// with the given `TargetState::id`, or the `event_whenstageclicked` scripts if this
+  /// is the stage.
+  pub fn start_click_scripts(&mut self, id: usize) {
+    let opcode = if self.data.is_stage {
+      "event_whenstageclicked"
+    } else {
+      "event_whenthisspriteclicked"
+    };
+    let scripts = if self.state.id == id {
+      &mut self.scripts
+    } else if let Some(clone) =
+      self.clones.iter_mut().find(|clone| clone.state.id == id)
+    {
+      &mut clone.scripts
+    } else {
       return;
+    };
+    start_hats(&self.data, scripts, opcode, |_| true, true);
+  }
+
+  /// Lowercased names of the broadcasts whose receiver scripts are still running.
+  pub fn running_broadcasts(&self) -> impl Iterator<Item = String> + '_ {
+    self
+      .clones
+      .iter()
+      .flat_map(|clone| &clone.scripts)
+      .chain(&self.scripts)
+      .filter_map(|script| broadcast_name(&self.data.blocks[script.hat - 1]))
+  }
+
+  /// Adds a clone and starts its `control_start_as_clone` scripts. The caller puts
+  /// it in `SharedState::layers`.
+  pub fn add_clone(&mut self, state: TargetState) {
+    let mut clone = SpriteClone {
+      state,
+      scripts: Vec::new(),
+    };
+    start_hats(
+      &self.data,
+      &mut clone.scripts,
+      "control_start_as_clone",
+      |_| true,
+      true,
+    );
+    self.clones.push(clone);
+  }
+
+  /// Runs the scripts of the original and every clone for one frame. `index` is the
+  /// position of this target in `Project::targets`.
+  pub fn execute_scripts(&mut self, shared: &mut SharedState, index: usize) {
+    execute_scripts(shared, &self.data, &mut self.state, &mut self.scripts);
+    // Other sprites and clones sense the ones that already ran as they are now.
+    record_drawable(shared, index, &self.data, &self.state);
+    for clone in &mut self.clones {
+      execute_scripts(shared, &self.data, &mut clone.state, &mut clone.scripts);
+      record_drawable(shared, index, &self.data, &clone.state);
     }
-    let texture =
-      &textures[data.costume_index_to_texture_index[&state.current_costume]];
-    let query = texture.texture.query();
-    state.size = clamp_size(state.size);
-    let scale = state.size / texture.bitmap_resolution as f64;
-    let width = query.width as f64 * scale / 100.;
-    let height = query.height as f64 * scale / 100.;
-    let x = config.stage_width as i32 / 2 + state.x as i32 - width as i32 / 2;
-    let y = (config.stage_height as i32 / 2 - state.y as i32) - height as i32 / 2;
-    let angle: f64;
-    let flip: bool;
-    match state.rotation_style {
-      RotationStyle::AllAround => {
-        angle = state.direction - 90.;
-        flip = false;
-      }
-      RotationStyle::DontRotate => {
-        angle = 0.;
-        flip = false;
-      }
-      RotationStyle::LeftRight => {
-        angle = 0.;
-        flip = state.direction < 0.;
-      }
-    }
-    canvas
-      .copy_ex(
-        &texture.texture,
-        None,
-        Rect::new(x, y, width as u32, height as u32),
-        angle,
-        None,
-        false,
-        flip,
-      )
-      .unwrap();
-    if let Some(ref mut say) = &mut state.say {
-      if say.texture.is_none() {
-        say.texture = Some(
-          texture_creator
-            .create_texture_from_surface(
-              font
-                .render(say.message.as_str())
-                .blended(Color::BLACK)
-                .unwrap(),
-            )
-            .unwrap(),
-        );
+    self.clones.retain(|clone| {
+      if clone.state.deleted {
+        shared.layers.retain(|&id| id != clone.state.id);
+        shared.drawables.remove(&clone.state.id);
       }
-      if let Some(texture) = &say.texture {
-        let query = texture.query();
-        canvas
-          .copy(texture, None, Rect::new(x, y, query.width, query.height))
-          .unwrap();
+      !clone.state.deleted
+    });
+  }
+}
+
+/// Starts a script for every `opcode` hat block accepted by `filter`. Scripts that
+/// are already running are restarted from their hat block if `restart` is set, like
+/// in Scratch.
+fn start_hats<F: Fn(&Block) -> bool>(
+  data: &TargetData,
+  scripts: &mut Vec<Script>,
+  opcode: &str,
+  filter: F,
+  restart: bool,
+) {
+  for (index, block) in data.blocks.iter().enumerate() {
+    if block.opcode != opcode || !filter(block) {
+      continue;
+    }
+    let hat = index + 1;
+    if let Some(script) = scripts.iter_mut().find(|script| script.hat == hat) {
+      if restart {
+        *script = Script::new(hat);
       }
+    } else {
+      scripts.push(Script::new(hat));
     }
   }
+}
 
-  pub fn start_scripts(&mut self) {
-    for (index, block) in self.data.blocks.iter().enumerate() {
-      match block.opcode.as_str() {
-        "event_whenflagclicked" => self.scripts.push(Script {
-          id: index + 1,
-          stack: vec![],
-          arguments: vec![],
-          arguments_start: 0,
-          refresh: true,
-        }),
-        _ => {}
-      }
+fn execute_scripts(
+  shared: &mut SharedState,
+  data: &TargetData,
+  state: &mut TargetState,
+  scripts: &mut Vec<Script>,
+) {
+  scripts.retain_mut(|script| loop {
+    if state.deleted {
+      return false;
+    }
+    let (terminate, refresh) = execute_script(shared, data, state, script);
+    if terminate || state.deleted || script.wait.is_some() || script.refresh && refresh
+    {
+      return !terminate && !state.deleted;
+    }
+  });
+}
+
+/// Shows a speech or thought bubble, or removes it if `message` is empty. Numbers
+/// are shown with two decimals, like in Scratch.
+fn set_bubble(
+  shared: &mut SharedState,
+  data: &TargetData,
+  state: &mut TargetState,
+  kind: SayKind,
+  message: &Value,
+  deadline: Option<f64>,
+) {
+  let message = match message {
+    Value::Float(number) if number.abs() >= 0.01 && number.fract() != 0. => {
+      format!("{number:.2}")
+    }
+    message => message.to_string(),
+  };
+  let message: String = message.chars().take(SAY_LIMIT).collect();
+  log::info!("{message}");
+  shared.says.push(SayEvent {
+    target: data.name.clone(),
+    kind,
+    message: message.clone(),
+  });
+  state.say = if message.is_empty() {
+    None
+  } else {
+    Some(Say {
+      kind,
+      message,
+      deadline,
+    })
+  };
+}
+
+/// The monitor of a variable or list, which is created hidden if the project does
+/// not have one yet. `target` is `None` for the stage's variables and lists.
+fn get_monitor<'a>(
+  shared: &'a mut SharedState,
+  target: Option<String>,
+  name: &str,
+  mode: MonitorMode,
+) -> &'a mut Monitor {
+  let is_list = mode == MonitorMode::List;
+  let index = shared.monitors.iter().position(|monitor| {
+    monitor.target == target
+      && monitor.name == name
+      && (monitor.mode == MonitorMode::List) == is_list
+  });
+  let index = index.unwrap_or_else(|| {
+    // Scratch looks for free space on the stage, new monitors are stacked instead.
+    let y = 5. + shared.monitors.len() as f64 * 30.;
+    shared.monitors.push(Monitor {
+      target,
+      name: name.to_string(),
+      mode,
+      x: 5.,
+      y,
+      width: 0.,
+      height: 0.,
+      visible: false,
+    });
+    shared.monitors.len() - 1
+  });
+  &mut shared.monitors[index]
+}
+
+/// Starts the sound of `data` named `sound`, or with that number if no sound has
+/// that name. Returns the handle of the playback, if there is such a sound.
+fn play_sound(
+  shared: &mut SharedState,
+  data: &TargetData,
+  state: &TargetState,
+  sound: &Value,
+) -> Option<usize> {
+  let count = data.sound_index_to_sound_index.len();
+  if count == 0 {
+    return None;
+  }
+  let name = sound.to_string();
+  let index = match data.sound_name_to_index.get(&name) {
+    Some(&index) => index,
+    None => {
+      // Like JavaScript's parseInt, which ignores anything after the digits.
+      let digits = name.trim_start();
+      let end = digits
+        .char_indices()
+        .find(|&(index, c)| {
+          !(c.is_ascii_digit() || index == 0 && (c == '-' || c == '+'))
+        })
+        .map_or(digits.len(), |(index, _)| index);
+      let number = digits[..end].parse::<i64>().ok()?;
+      (number - 1).rem_euclid(count as i64) as usize
+    }
+  };
+  let handle = shared.next_sound;
+  shared.next_sound += 1;
+  shared.sounds.push(SoundInstruction::Play {
+    handle,
+    target: state.id,
+    sound: data.sound_index_to_sound_index[index],
+  });
+  shared.playing_sounds.insert(handle);
+  Some(handle)
+}
+
+/// Casts a colour input, either "#rrggbb" or a number 0xAARRGGBB, to RGBA like
+/// `Cast.toRgbColorObject` in Scratch. A zero alpha counts as opaque.
+fn to_rgba(value: &Value) -> [u8; 4] {
+  if let Value::String(string) = value {
+    if let Some(hex) = string.strip_prefix('#') {
+      let hex = if hex.len() == 3 {
+        hex.chars().flat_map(|c| [c, c]).collect()
+      } else {
+        hex.to_string()
+      };
+      return match u32::from_str_radix(&hex, 16) {
+        Ok(rgb) if hex.len() == 6 => {
+          let [_, r, g, b] = rgb.to_be_bytes();
+          [r, g, b, 255]
+        }
+        _ => [0, 0, 0, 255],
+      };
+    }
+  }
+  let [a, r, g, b] = (value.to_f64() as i64 as u32).to_be_bytes();
+  [r, g, b, if a == 0 { 255 } else { a }]
+}
+
+/// Scratch keeps at least this many pixels of a sprite on the stage.
+const FENCE_WIDTH: f64 = 15.;
+
+/// Moves a sprite, keeping it on the stage like every motion block does. The stage
+/// cannot move.
+fn set_xy(
+  shared: &mut SharedState,
+  data: &TargetData,
+  state: &mut TargetState,
+  x: f64,
+  y: f64,
+) {
+  if data.is_stage {
+    return;
+  }
+  (state.x, state.y) = keep_in_fence(shared, data, state, x, y);
+  if state.pen.is_down {
+    update_pen(shared, state);
+  }
+}
+
+/// Bounds of a sprite as it is drawn, as (left, right, bottom, top) in stage
+/// coordinates.
+fn bounds(
+  shared: &SharedState,
+  data: &TargetData,
+  state: &TargetState,
+) -> (f64, f64, f64, f64) {
+  let stamp = Stamp::new(data, state);
+  stamp.bounds(&shared.textures[stamp.texture])
+}
+
+/// The position closest to (x, y) that leaves part of the sprite on the stage, like
+/// `getFencedPositionOfDrawable` in scratch-render.
+fn keep_in_fence(
+  shared: &SharedState,
+  data: &TargetData,
+  state: &TargetState,
+  mut x: f64,
+  mut y: f64,
+) -> (f64, f64) {
+  let (left, right, bottom, top) = bounds(shared, data, state);
+  let (dx, dy) = (x - state.x, y - state.y);
+  let inset = ((right - left).min(top - bottom) / 2.).floor();
+  let fence_x = shared.stage_width / 2. - FENCE_WIDTH.min(inset);
+  if right + dx < -fence_x {
+    x = (state.x - (fence_x + right)).ceil();
+  } else if left + dx > fence_x {
+    x = (state.x + (fence_x - left)).floor();
+  }
+  let fence_y = shared.stage_height / 2. - FENCE_WIDTH.min(inset);
+  if top + dy < -fence_y {
+    y = (state.y - (fence_y + top)).ceil();
+  } else if bottom + dy > fence_y {
+    y = (state.y + (fence_y - bottom)).floor();
+  }
+  (x, y)
+}
+
+/// The position a `motion_goto`, `motion_glideto` or `motion_pointtowards` menu
+/// refers to: a random position, the mouse pointer or a sprite by name.
+fn motion_target(shared: &SharedState, name: &str) -> Option<(f64, f64)> {
+  match name {
+    "_random_" => Some((
+      (shared.stage_width * (shared.random.fraction() - 0.5)).round(),
+      (shared.stage_height * (shared.random.fraction() - 0.5)).round(),
+    )),
+    "_mouse_" => Some((shared.mouse_x, shared.mouse_y)),
+    name => {
+      let &target = shared.target_name_to_target_index.get(name)?;
+      shared
+        .drawables
+        .values()
+        .find(|drawable| {
+          drawable.target == target && !drawable.is_clone && !drawable.is_stage
+        })
+        .map(|drawable| (drawable.stamp.x, drawable.stamp.y))
     }
   }
+}
 
-  pub fn execute_scripts(&mut self, shared: &mut SharedState) {
-    self.scripts.retain_mut(|script| loop {
-      let (terminate, refresh) =
-        execute_script(shared, &self.data, &mut self.state, script);
-      if terminate || script.refresh && refresh {
-        return !terminate;
+/// Points a sprite touching an edge of the stage away from the nearest edge, and
+/// moves it back inside.
+fn if_on_edge_bounce(
+  shared: &mut SharedState,
+  data: &TargetData,
+  state: &mut TargetState,
+) {
+  if data.is_stage {
+    return;
+  }
+  let (left, right, bottom, top) = bounds(shared, data, state);
+  let (half_width, half_height) = (shared.stage_width / 2., shared.stage_height / 2.);
+  let distances = [
+    half_width + left,
+    half_height - top,
+    half_width - right,
+    half_height + bottom,
+  ];
+  let mut nearest = 0;
+  for (edge, &distance) in distances.iter().enumerate() {
+    if distance.max(0.) < distances[nearest].max(0.) {
+      nearest = edge;
+    }
+  }
+  if distances[nearest] > 0. {
+    return;
+  }
+  let radians = (90. - state.direction).to_radians();
+  let (mut dx, mut dy) = (radians.cos(), -radians.sin());
+  match nearest {
+    0 => dx = dx.abs().max(0.2),
+    1 => dy = dy.abs().max(0.2),
+    2 => dx = -dx.abs().max(0.2),
+    _ => dy = -dy.abs().max(0.2),
+  }
+  if let Some(direction) = get_direction(dy.atan2(dx).to_degrees() + 90.) {
+    state.direction = direction;
+  }
+  set_xy(shared, data, state, state.x, state.y);
+}
+
+/// Moves `id` to `index` in `SharedState::layers`, staying above the stage.
+pub fn set_layer(layers: &mut Vec<usize>, id: usize, index: f64) {
+  let Some(old_index) = layers.iter().position(|&layer| layer == id) else {
+    return;
+  };
+  layers.remove(old_index);
+  let index = index.clamp(1., layers.len() as f64) as usize;
+  layers.insert(index, id);
+}
+
+/// The costume a `looks_switchcostumeto` or `looks_switchbackdropto` argument refers
+/// to, following `_setCostumeOrBackdrop` in scratch-vm: costume names win over
+/// numbers, numbers count from 1 and wrap around, and backdrops also accept "next
+/// backdrop", "previous backdrop" and "random backdrop".
+fn costume_index<F: Fn(&str) -> Option<usize>>(
+  costume: &Value,
+  count: usize,
+  current: usize,
+  lookup: F,
+  backdrop: bool,
+  random: &Random,
+) -> Option<usize> {
+  let wrap = |index: f64| {
+    let index = (index + 0.5).floor();
+    if index.is_finite() && count > 0 {
+      Some((index as i64).rem_euclid(count as i64) as usize)
+    } else {
+      None
+    }
+  };
+  let name = match costume {
+    Value::Float(number) => return wrap(number - 1.),
+    costume => costume.to_string(),
+  };
+  if let Some(index) = lookup(name.as_str()) {
+    return Some(index);
+  }
+  match name.as_str() {
+    "next backdrop" if backdrop => wrap(current as f64 + 1.),
+    "previous backdrop" if backdrop => wrap(current as f64 - 1.),
+    "random backdrop" if backdrop => {
+      if count <= 1 {
+        return None;
       }
-    });
+      // Never picks the current backdrop.
+      let index = random.below(count - 1);
+      Some(if index >= current { index + 1 } else { index })
+    }
+    name => wrap(name.trim().parse::<f64>().ok()? - 1.),
+  }
+}
+
+/// Converts a key to the name Scratch uses for it, like `_keyArgToScratchKey` in
+/// scratch-vm: "space", "left arrow", "up arrow", "right arrow", "down arrow",
+/// "enter", "any", or a single lowercase character. Numbers are key codes.
+pub fn scratch_key(key: &Value) -> String {
+  if let Value::Float(code) = key {
+    match *code as u32 {
+      32 => return String::from("space"),
+      37 => return String::from("left arrow"),
+      38 => return String::from("up arrow"),
+      39 => return String::from("right arrow"),
+      40 => return String::from("down arrow"),
+      code @ 48..=90 => return char::from(code as u8).to_lowercase().collect(),
+      _ => {}
+    }
+  }
+  let key = key.to_string();
+  match key.as_str() {
+    "space" | "left arrow" | "up arrow" | "right arrow" | "down arrow" | "enter"
+    | "any" => key,
+    _ => match key.chars().next() {
+      Some(' ') => String::from("space"),
+      Some(key) => key.to_lowercase().collect(),
+      None => String::new(),
+    },
+  }
+}
+
+/// The lowercased broadcast name of an `event_whenbroadcastreceived` hat block.
+fn broadcast_name(block: &Block) -> Option<String> {
+  if block.opcode != "event_whenbroadcastreceived" {
+    return None;
+  }
+  match block.inputs.get("BROADCAST_OPTION") {
+    Some(Input::Broadcast(broadcast)) => Some(broadcast.name.to_lowercase()),
+    _ => None,
   }
 }
 
@@ -151,6 +570,161 @@ fn execute_script(
         script.id = jump_id;
       }
     }
+    "control_repeat_until" => {
+      if aux_bool(shared, data, state, &block.inputs["CONDITION"], script) {
+        script.wait = None;
+        script.id = block.next;
+      } else if let Some(Input::Block(jump_id)) = block.inputs.get("SUBSTACK") {
+        script.stack.push(StackFrame::Goto(script.id));
+        script.id = *jump_id;
+      } else {
+        // An empty loop would never yield, wait for the condition instead.
+        script.wait = Some(Wait::Condition);
+      }
+    }
+    "control_wait" => {
+      if let Some(Wait::Timer(end)) = script.wait {
+        if shared.time >= end {
+          script.wait = None;
+          script.id = block.next;
+        }
+      } else {
+        let duration = aux_f64(shared, data, state, &block.inputs["DURATION"], script);
+        script.wait = Some(Wait::Timer(shared.time + duration));
+        refresh = true;
+      }
+    }
+    "music_playNoteForBeats" | "music_playDrumForBeats" | "music_restForBeats" => {
+      if let Some(Wait::Timer(end)) = script.wait {
+        if shared.time >= end {
+          script.wait = None;
+          script.id = block.next;
+        }
+      } else {
+        let beats = aux_f64(shared, data, state, &block.inputs["BEATS"], script);
+        let duration = 60. / shared.tempo * beats.clamp(0., MAX_BEATS);
+        if block.opcode == "music_playNoteForBeats" {
+          let note = aux_f64(shared, data, state, &block.inputs["NOTE"], script);
+          if duration > 0. {
+            shared.sounds.push(SoundInstruction::Note {
+              target: state.id,
+              instrument: state.instrument,
+              note: note.clamp(MIN_NOTE, MAX_NOTE),
+              duration,
+            });
+          }
+        } else if block.opcode == "music_playDrumForBeats" {
+          let drum = aux_f64(shared, data, state, &block.inputs["DRUM"], script);
+          shared.sounds.push(SoundInstruction::Drum {
+            target: state.id,
+            drum: menu_index(drum, DRUMS.len()),
+          });
+        }
+        script.wait = Some(Wait::Timer(shared.time + duration));
+        refresh = true;
+      }
+    }
+    "looks_sayforsecs" | "looks_thinkforsecs" => {
+      if let Some(Wait::Timer(end)) = script.wait {
+        if shared.time >= end {
+          // Only remove the bubble if no other block has replaced it.
+          if state.say.as_ref().and_then(|say| say.deadline) == Some(end) {
+            let kind = state.say.as_ref().unwrap().kind;
+            set_bubble(
+              shared,
+              data,
+              state,
+              kind,
+              &Value::String(String::new()),
+              None,
+            );
+          }
+          script.wait = None;
+          script.id = block.next;
+        }
+      } else {
+        let kind = if block.opcode == "looks_sayforsecs" {
+          SayKind::Say
+        } else {
+          SayKind::Think
+        };
+        let message = aux_value(shared, data, state, &block.inputs["MESSAGE"], script);
+        let duration = aux_f64(shared, data, state, &block.inputs["SECS"], script);
+        let end = shared.time + duration;
+        set_bubble(shared, data, state, kind, &message, Some(end));
+        script.wait = Some(Wait::Timer(end));
+        refresh = true;
+      }
+    }
+    "sound_playuntildone" => {
+      if let Some(Wait::Sound(handle)) = script.wait {
+        if !shared.playing_sounds.contains(&handle) {
+          script.wait = None;
+          script.id = block.next;
+        }
+      } else {
+        let sound = aux_value(shared, data, state, &block.inputs["SOUND_MENU"], script);
+        match play_sound(shared, data, state, &sound) {
+          Some(handle) => script.wait = Some(Wait::Sound(handle)),
+          None => script.id = block.next,
+        }
+      }
+    }
+    "motion_glidesecstoxy" | "motion_glideto" => {
+      if let Some(Wait::Glide {
+        start,
+        duration,
+        from,
+        to,
+      }) = script.wait
+      {
+        let fraction = (shared.time - start) / duration;
+        if fraction < 1. {
+          let x = from.0 + fraction * (to.0 - from.0);
+          let y = from.1 + fraction * (to.1 - from.1);
+          set_xy(shared, data, state, x, y);
+        } else {
+          set_xy(shared, data, state, to.0, to.1);
+          script.wait = None;
+          script.id = block.next;
+        }
+      } else {
+        let duration = aux_f64(shared, data, state, &block.inputs["SECS"], script);
+        let to = if block.opcode == "motion_glidesecstoxy" {
+          Some((
+            aux_f64(shared, data, state, &block.inputs["X"], script),
+            aux_f64(shared, data, state, &block.inputs["Y"], script),
+          ))
+        } else {
+          let to = aux_string(shared, data, state, &block.inputs["TO"], script);
+          motion_target(shared, &to)
+        };
+        match to {
+          Some(to) if duration > 0. => {
+            script.wait = Some(Wait::Glide {
+              start: shared.time,
+              duration,
+              from: (state.x, state.y),
+              to,
+            });
+          }
+          Some((x, y)) => {
+            set_xy(shared, data, state, x, y);
+            script.id = block.next;
+          }
+          None => script.id = block.next,
+        }
+      }
+      refresh = true;
+    }
+    "control_wait_until" => {
+      if aux_bool(shared, data, state, &block.inputs["CONDITION"], script) {
+        script.wait = None;
+        script.id = block.next;
+      } else {
+        script.wait = Some(Wait::Condition);
+      }
+    }
     "control_forever" => {
       let jump_id = aux_id(&block.inputs["SUBSTACK"]);
       script.stack.push(StackFrame::Goto(script.id));
@@ -197,6 +771,27 @@ fn execute_script(
         script.refresh = false;
       }
     }
+    "event_broadcastandwait" => match &script.wait {
+      Some(Wait::Broadcast(broadcast)) => {
+        if !shared.running_broadcasts.contains(broadcast) {
+          script.wait = None;
+          script.id = block.next;
+        }
+      }
+      // A wait left over from another block counts as not having started yet.
+      _ => {
+        let broadcast = aux_string(
+          shared,
+          data,
+          state,
+          &block.inputs["BROADCAST_INPUT"],
+          script,
+        )
+        .to_lowercase();
+        shared.broadcasts.push(broadcast.clone());
+        script.wait = Some(Wait::Broadcast(broadcast));
+      }
+    },
     _ => {
       refresh = execute_block(shared, data, state, script.id, &script);
       script.id = block.next;
@@ -289,26 +884,83 @@ fn execute_block(
   let mut refresh = false;
   let block = &data.blocks[id - 1];
   match block.opcode.as_str() {
-    "event_whenflagclicked" => {}
+    "event_whenflagclicked"
+    | "event_whenbroadcastreceived"
+    | "event_whenkeypressed"
+    | "event_whenthisspriteclicked"
+    | "event_whenstageclicked"
+    | "control_start_as_clone" => {}
+    "event_broadcast" => {
+      let broadcast =
+        aux_string(shared, data, state, &block.inputs["BROADCAST_INPUT"], script);
+      shared.broadcasts.push(broadcast.to_lowercase());
+    }
     "motion_gotoxy" => {
-      state.x = aux_f64(shared, data, state, &block.inputs["X"], script);
-      state.y = aux_f64(shared, data, state, &block.inputs["Y"], script);
+      let x = aux_f64(shared, data, state, &block.inputs["X"], script);
+      let y = aux_f64(shared, data, state, &block.inputs["Y"], script);
+      set_xy(shared, data, state, x, y);
       refresh = true;
     }
     "motion_setx" => {
-      state.x = aux_f64(shared, data, state, &block.inputs["X"], script);
+      let x = aux_f64(shared, data, state, &block.inputs["X"], script);
+      set_xy(shared, data, state, x, state.y);
       refresh = true;
     }
     "motion_sety" => {
-      state.y = aux_f64(shared, data, state, &block.inputs["Y"], script);
+      let y = aux_f64(shared, data, state, &block.inputs["Y"], script);
+      set_xy(shared, data, state, state.x, y);
       refresh = true;
     }
     "motion_changexby" => {
-      state.x += aux_f64(shared, data, state, &block.inputs["DX"], script);
+      let x = state.x + aux_f64(shared, data, state, &block.inputs["DX"], script);
+      set_xy(shared, data, state, x, state.y);
       refresh = true;
     }
     "motion_changeyby" => {
-      state.y += aux_f64(shared, data, state, &block.inputs["DY"], script);
+      let y = state.y + aux_f64(shared, data, state, &block.inputs["DY"], script);
+      set_xy(shared, data, state, state.x, y);
+      refresh = true;
+    }
+    "motion_movesteps" => {
+      let steps = aux_f64(shared, data, state, &block.inputs["STEPS"], script);
+      let radians = (90. - state.direction).to_radians();
+      let x = state.x + steps * radians.cos();
+      let y = state.y + steps * radians.sin();
+      set_xy(shared, data, state, x, y);
+      refresh = true;
+    }
+    "motion_goto" => {
+      let to = aux_string(shared, data, state, &block.inputs["TO"], script);
+      if let Some((x, y)) = motion_target(shared, &to) {
+        set_xy(shared, data, state, x, y);
+      }
+      refresh = true;
+    }
+    "motion_pointtowards" => {
+      let towards = aux_string(shared, data, state, &block.inputs["TOWARDS"], script);
+      let direction = if towards == "_random_" {
+        Some((shared.random.fraction() * 360. - 180.).round())
+      } else {
+        motion_target(shared, &towards)
+          .map(|(x, y)| 90. - (y - state.y).atan2(x - state.x).to_degrees())
+      };
+      if let Some(direction) = direction.and_then(get_direction) {
+        state.direction = direction;
+      }
+      refresh = true;
+    }
+    "motion_ifonedgebounce" => {
+      if_on_edge_bounce(shared, data, state);
+      refresh = true;
+    }
+    "motion_setrotationstyle" => {
+      aux_field(block, "STYLE", |style| {
+        state.rotation_style = match style {
+          "left-right" => RotationStyle::LeftRight,
+          "don't rotate" => RotationStyle::DontRotate,
+          _ => RotationStyle::AllAround,
+        }
+      });
       refresh = true;
     }
     "motion_pointindirection" => {
@@ -343,18 +995,86 @@ fn execute_block(
       }
       refresh = true;
     }
-    "looks_say" => {
-      let message =
-        aux_string(shared, data, state, &block.inputs["MESSAGE"], script).to_string();
-      log::info!("{message}");
-      state.say = if message.len() == 0 {
-        None
+    "control_create_clone_of" => {
+      let option =
+        aux_string(shared, data, state, &block.inputs["CLONE_OPTION"], script);
+      if option == "_myself_" {
+        if !data.is_stage {
+          shared.clone_requests.push(CloneRequest {
+            target: data.name.clone(),
+            parent: Some(state.id),
+            state: Some(state.clone_state()),
+          });
+        }
       } else {
-        Some(Say {
-          message,
-          texture: None,
-        })
+        shared.clone_requests.push(CloneRequest {
+          target: option,
+          parent: None,
+          state: None,
+        });
+      }
+    }
+    "looks_gotofrontback" => {
+      if !data.is_stage {
+        let index = aux_field(block, "FRONT_BACK", |front_back| match front_back {
+          "front" => shared.layers.len() as f64,
+          _ => 1.,
+        });
+        set_layer(&mut shared.layers, state.id, index);
+      }
+    }
+    "looks_goforwardbackward" => {
+      if !data.is_stage {
+        let layers = aux_f64(shared, data, state, &block.inputs["NUM"], script).trunc();
+        let layers =
+          aux_field(
+            block,
+            "FORWARD_BACKWARD",
+            |forward_backward| match forward_backward {
+              "forward" => layers,
+              _ => -layers,
+            },
+          );
+        if let Some(index) = shared.layers.iter().position(|&id| id == state.id) {
+          set_layer(&mut shared.layers, state.id, index as f64 + layers);
+        }
+      }
+    }
+    "data_showvariable" | "data_hidevariable" => {
+      let Input::Variable(variable) = &block.inputs["VARIABLE"] else {
+        panic!()
+      };
+      let target = (!variable.is_global).then(|| data.name.clone());
+      let monitor = get_monitor(shared, target, &variable.name, MonitorMode::Default);
+      monitor.visible = block.opcode == "data_showvariable";
+    }
+    "data_showlist" | "data_hidelist" => {
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!()
       };
+      let target = (!list.is_global).then(|| data.name.clone());
+      let monitor = get_monitor(shared, target, &list.name, MonitorMode::List);
+      monitor.visible = block.opcode == "data_showlist";
+    }
+    "sensing_setdragmode" => {
+      state.draggable = aux_field(block, "DRAG_MODE", |mode| mode == "draggable");
+    }
+    "sensing_resettimer" => {
+      shared.timer_start = shared.time;
+    }
+    "control_delete_this_clone" => {
+      if state.is_clone {
+        state.deleted = true;
+      }
+    }
+    "looks_say" | "looks_think" => {
+      let kind = if block.opcode == "looks_say" {
+        SayKind::Say
+      } else {
+        SayKind::Think
+      };
+      let message = aux_value(shared, data, state, &block.inputs["MESSAGE"], script);
+      set_bubble(shared, data, state, kind, &message, None);
       refresh = true;
     }
     "data_setvariableto" => {
@@ -370,44 +1090,242 @@ fn execute_block(
       });
     }
     "data_deletealloflist" => {
-      let Input::List(list) = &block.inputs["LIST"] else { panic!(); };
-      if list.is_global {
-        shared.global_lists[list.id].clear();
-      } else {
-        state.lists[list.id].clear();
-      }
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!();
+      };
+      get_list_mut(shared, state, list).clear();
     }
     "data_addtolist" => {
       let Input::List(list) = &block.inputs["LIST"] else { panic!(); };
       let value = aux_value(shared, data, state, &block.inputs["ITEM"], script);
-      if list.is_global {
-        shared.global_lists[list.id].push(value);
-      } else {
-        state.lists[list.id].push(value);
+      let list = get_list_mut(shared, state, list);
+      if list.len() < LIST_ITEM_LIMIT {
+        list.push(value);
+      }
+    }
+    "data_deleteoflist" => {
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!();
+      };
+      let index = aux_value(shared, data, state, &block.inputs["INDEX"], script);
+      let length = get_list(shared, state, list).len();
+      let index = list_index(&index, length, true, &shared.random);
+      let list = get_list_mut(shared, state, list);
+      match index {
+        ListIndex::Index(index) => {
+          list.remove(index);
+        }
+        ListIndex::All => list.clear(),
+        ListIndex::Invalid => {}
       }
     }
+    "data_insertatlist" => {
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!();
+      };
+      let item = aux_value(shared, data, state, &block.inputs["ITEM"], script);
+      let index = aux_value(shared, data, state, &block.inputs["INDEX"], script);
+      let length = get_list(shared, state, list).len();
+      let index = list_index(&index, length + 1, false, &shared.random);
+      let list = get_list_mut(shared, state, list);
+      if let ListIndex::Index(index) = index {
+        if index < LIST_ITEM_LIMIT {
+          list.insert(index, item);
+          list.truncate(LIST_ITEM_LIMIT);
+        }
+      }
+    }
+    "data_replaceitemoflist" => {
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!();
+      };
+      let item = aux_value(shared, data, state, &block.inputs["ITEM"], script);
+      let index = aux_value(shared, data, state, &block.inputs["INDEX"], script);
+      let length = get_list(shared, state, list).len();
+      let index = list_index(&index, length, false, &shared.random);
+      let list = get_list_mut(shared, state, list);
+      if let ListIndex::Index(index) = index {
+        list[index] = item;
+      }
+    }
+    "looks_switchcostumeto" => {
+      let costume = aux_value(shared, data, state, &block.inputs["COSTUME"], script);
+      let names = &data.costume_index_to_name;
+      let lookup = |name: &str| data.costume_name_to_index.get(name).copied();
+      if let Some(index) = costume_index(
+        &costume,
+        names.len(),
+        state.current_costume,
+        lookup,
+        false,
+        &shared.random,
+      ) {
+        state.current_costume = index;
+      }
+    }
+    "looks_nextcostume" => {
+      state.current_costume =
+        (state.current_costume + 1) % data.costume_index_to_name.len();
+    }
+    "looks_switchbackdropto" => {
+      let backdrop = aux_value(shared, data, state, &block.inputs["BACKDROP"], script);
+      let names = &shared.backdrop_names;
+      let lookup = |name: &str| names.iter().position(|backdrop| backdrop == name);
+      if let Some(index) = costume_index(
+        &backdrop,
+        names.len(),
+        shared.backdrop,
+        lookup,
+        true,
+        &shared.random,
+      ) {
+        shared.backdrop = index;
+      }
+    }
+    "looks_nextbackdrop" => {
+      shared.backdrop = (shared.backdrop + 1) % shared.backdrop_names.len();
+    }
+    "looks_show" => {
+      state.visible = true;
+    }
+    "looks_hide" => {
+      state.visible = false;
+    }
+    "looks_changesizeby" => {
+      let change = aux_f64(shared, data, state, &block.inputs["CHANGE"], script);
+      state.size = clamp_size(state.size + change);
+    }
+    "looks_seteffectto" => {
+      let value = aux_f64(shared, data, state, &block.inputs["VALUE"], script);
+      aux_field(block, "EFFECT", |effect| {
+        if let Some(effect) = state.effects.get_mut(effect) {
+          *effect = value;
+        }
+      });
+      state.effects.clamp();
+    }
+    "looks_changeeffectby" => {
+      let change = aux_f64(shared, data, state, &block.inputs["CHANGE"], script);
+      aux_field(block, "EFFECT", |effect| {
+        if let Some(effect) = state.effects.get_mut(effect) {
+          *effect += change;
+        }
+      });
+      state.effects.clamp();
+    }
+    "looks_cleargraphiceffects" => {
+      state.effects = Effects::default();
+    }
+    "sound_play" => {
+      let sound = aux_value(shared, data, state, &block.inputs["SOUND_MENU"], script);
+      play_sound(shared, data, state, &sound);
+    }
+    "sound_stopallsounds" => {
+      shared.sounds.push(SoundInstruction::StopAll);
+      shared.playing_sounds.clear();
+    }
+    "sound_setvolumeto" => {
+      let volume = aux_f64(shared, data, state, &block.inputs["VOLUME"], script);
+      state.volume = volume.clamp(0., 100.);
+    }
+    "sound_changevolumeby" => {
+      let change = aux_f64(shared, data, state, &block.inputs["VOLUME"], script);
+      state.volume = (state.volume + change).clamp(0., 100.);
+    }
+    "sound_seteffectto" => {
+      let value = aux_f64(shared, data, state, &block.inputs["VALUE"], script);
+      aux_field(block, "EFFECT", |effect| {
+        if let Some(effect) = state.sound_effects.get_mut(effect) {
+          *effect = value;
+        }
+      });
+      state.sound_effects.clamp();
+    }
+    "sound_changeeffectby" => {
+      let change = aux_f64(shared, data, state, &block.inputs["VALUE"], script);
+      aux_field(block, "EFFECT", |effect| {
+        if let Some(effect) = state.sound_effects.get_mut(effect) {
+          *effect += change;
+        }
+      });
+      state.sound_effects.clamp();
+    }
+    "sound_cleareffects" => {
+      state.sound_effects = SoundEffects::default();
+    }
+    "music_setInstrument" => {
+      let instrument =
+        aux_f64(shared, data, state, &block.inputs["INSTRUMENT"], script);
+      state.instrument = menu_index(instrument, INSTRUMENTS.len());
+    }
+    "music_setTempo" => {
+      let tempo = aux_f64(shared, data, state, &block.inputs["TEMPO"], script);
+      shared.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+    }
+    "music_changeTempo" => {
+      let change = aux_f64(shared, data, state, &block.inputs["TEMPO"], script);
+      shared.tempo = (shared.tempo + change).clamp(MIN_TEMPO, MAX_TEMPO);
+    }
     "looks_setsizeto" => {
       let size = aux_f64(shared, data, state, &block.inputs["SIZE"], script);
-      state.size = size;
+      state.size = clamp_size(size);
     }
     "pen_clear" => {
       shared.pen.clear();
+      shared.pen.push(PenInstruction::Clear);
+    }
+    "pen_stamp" => {
+      shared
+        .pen
+        .push(PenInstruction::Stamp(Stamp::new(data, state)));
     }
     "pen_setPenSizeTo" => {
       let size = aux_f64(shared, data, state, &block.inputs["SIZE"], script);
-      if 0. < size {
-        state.pen.size = size as u32;
+      state.pen.size = size.clamp(MIN_PEN_SIZE, MAX_PEN_SIZE);
+    }
+    "pen_setPenColorToColor" => {
+      let color = aux_value(shared, data, state, &block.inputs["COLOR"], script);
+      let [r, g, b, a] = to_rgba(&color);
+      let (hue, saturation, value) =
+        rgb_to_hsv([r, g, b].map(|channel| channel as f64 / 255.));
+      state.pen.color = hue * 100.;
+      state.pen.saturation = saturation * 100.;
+      state.pen.brightness = value * 100.;
+      state.pen.transparency = 100. * (1. - a as f64 / 255.);
+      state.pen.shade = state.pen.brightness / 2.;
+    }
+    "pen_setPenColorParamTo" | "pen_changePenColorParamBy" => {
+      let param = aux_string(shared, data, state, &block.inputs["COLOR_PARAM"], script);
+      let value = aux_f64(shared, data, state, &block.inputs["VALUE"], script);
+      let change = block.opcode == "pen_changePenColorParamBy";
+      state.pen.set_color_param(&param, value, change);
+    }
+    "pen_setPenHueToNumber" | "pen_changePenHueBy" => {
+      let hue = aux_f64(shared, data, state, &block.inputs["HUE"], script);
+      if block.opcode == "pen_setPenHueToNumber" {
+        state.pen.set_color_param("color", hue / 2., false);
+        state.pen.set_color_param("transparency", 0., false);
+      } else {
+        state.pen.set_color_param("color", hue / 2., true);
+      }
+      state.pen.update_legacy_color();
+    }
+    "pen_setPenShadeToNumber" | "pen_changePenShadeBy" => {
+      let mut shade = aux_f64(shared, data, state, &block.inputs["SHADE"], script);
+      if block.opcode == "pen_changePenShadeBy" {
+        shade += state.pen.shade;
       }
+      state.pen.shade = shade.rem_euclid(200.);
+      state.pen.update_legacy_color();
     }
     "pen_penDown" => {
       state.pen.is_down = true;
       state.pen.x = state.x;
       state.pen.y = state.y;
+      // A dot where the pen went down.
+      update_pen(shared, state);
     }
     "pen_penUp" => {
-      if state.pen.is_down {
-        update_pen(shared, state);
-      }
       state.pen.is_down = false;
     }
     _ => panic!("I don't know how to execute: {block:#?}"),
@@ -415,18 +1333,20 @@ fn execute_block(
   refresh
 }
 
-fn update_pen(shared: &mut SharedState, state: &mut TargetState) {
-  shared.pen.push_back(PenInstruction {
+/// Draws a line from where the pen last was to the position of the sprite.
+pub fn update_pen(shared: &mut SharedState, state: &mut TargetState) {
+  let [r, g, b, a] = state.pen.rgba();
+  shared.pen.push(PenInstruction::Line(PenLine {
     size: state.pen.size,
-    r: state.pen.r,
-    g: state.pen.g,
-    b: state.pen.b,
-    a: state.pen.a,
+    r,
+    g,
+    b,
+    a,
     x1: state.pen.x,
     y1: state.pen.y,
     x2: state.x,
     y2: state.y,
-  });
+  }));
   state.pen.x = state.x;
   state.pen.y = state.y;
 }
@@ -514,9 +1434,9 @@ fn evaluate_block(
       if low == high {
         low
       } else if from.is_int() && to.is_int() {
-        unsafe { (low as i32 + libc::rand() % (high as i32 + 1)) as f64 }
+        low + (shared.random.fraction() * (high + 1. - low)).floor()
       } else {
-        unsafe { low + (libc::rand() as f64 / i32::MAX as f64) * (high - low) }
+        low + shared.random.fraction() * (high - low)
       }
     }),
     "operator_join" => Value::String(aux_map_as_str(
@@ -602,6 +1522,88 @@ fn evaluate_block(
         s => panic!("I don't know how to perform: {s}"),
       }))
     }
+    "motion_goto_menu" => aux_value(shared, data, state, &block.inputs["TO"], script),
+    "motion_glideto_menu" => {
+      aux_value(shared, data, state, &block.inputs["TO"], script)
+    }
+    "motion_pointtowards_menu" => {
+      aux_value(shared, data, state, &block.inputs["TOWARDS"], script)
+    }
+    "control_create_clone_of_menu" => {
+      aux_value(shared, data, state, &block.inputs["CLONE_OPTION"], script)
+    }
+    "sensing_keypressed" => {
+      let key = scratch_key(&aux_value(
+        shared,
+        data,
+        state,
+        &block.inputs["KEY_OPTION"],
+        script,
+      ));
+      Value::Bool(if key == "any" {
+        !shared.pressed_keys.is_empty()
+      } else {
+        shared.pressed_keys.contains(&key)
+      })
+    }
+    "sensing_keyoptions" => {
+      aux_value(shared, data, state, &block.inputs["KEY_OPTION"], script)
+    }
+    "sensing_touchingobject" => {
+      let object = aux_value(
+        shared,
+        data,
+        state,
+        &block.inputs["TOUCHINGOBJECTMENU"],
+        script,
+      );
+      Value::Bool(touching_object(shared, data, state, &object.to_string()))
+    }
+    "sensing_touchingobjectmenu" => aux_value(
+      shared,
+      data,
+      state,
+      &block.inputs["TOUCHINGOBJECTMENU"],
+      script,
+    ),
+    "sensing_touchingcolor" => {
+      let color = aux_value(shared, data, state, &block.inputs["COLOR"], script);
+      let [r, g, b, _] = to_rgba(&color);
+      Value::Bool(touching_color(shared, data, state, [r, g, b], None))
+    }
+    "sensing_coloristouchingcolor" => {
+      let mask = aux_value(shared, data, state, &block.inputs["COLOR"], script);
+      let [mask_r, mask_g, mask_b, _] = to_rgba(&mask);
+      let color = aux_value(shared, data, state, &block.inputs["COLOR2"], script);
+      let [r, g, b, _] = to_rgba(&color);
+      Value::Bool(touching_color(
+        shared,
+        data,
+        state,
+        [r, g, b],
+        Some([mask_r, mask_g, mask_b]),
+      ))
+    }
+    "sensing_mousex" => Value::Float(shared.mouse_x),
+    "sensing_mousey" => Value::Float(shared.mouse_y),
+    "sensing_mousedown" => Value::Bool(shared.mouse_down),
+    "sensing_timer" => Value::Float(shared.time - shared.timer_start),
+    "sensing_dayssince2000" => Value::Float(days_since_2000(shared.unix_time)),
+    "sensing_current" => {
+      let now = DateTime::from_seconds(shared.unix_time + shared.utc_offset);
+      Value::Float(aux_field(block, "CURRENTMENU", |menu| {
+        match menu.to_lowercase().as_str() {
+          "year" => now.year,
+          "month" => now.month,
+          "date" => now.date,
+          "dayofweek" => now.day_of_week,
+          "hour" => now.hour,
+          "minute" => now.minute,
+          "second" => now.second,
+          _ => 0,
+        }
+      }) as f64)
+    }
     "motion_xposition" => Value::Float(limit_precision(state.x)),
     "motion_yposition" => Value::Float(limit_precision(state.y)),
     "motion_direction" => Value::Float(state.direction),
@@ -612,26 +1614,82 @@ fn evaluate_block(
       }
       _ => panic!(),
     }),
+    "looks_backdropnumbername" => aux_field(block, "NUMBER_NAME", |s| match s {
+      "number" => Value::Float(1. + shared.backdrop as f64),
+      "name" => Value::String(shared.backdrop_names[shared.backdrop].clone()),
+      _ => panic!(),
+    }),
+    "looks_size" => Value::Float((state.size + 0.5).floor()),
+    "pen_menu_colorParam" => {
+      aux_value(shared, data, state, &block.inputs["colorParam"], script)
+    }
+    "sound_sounds_menu" => {
+      aux_value(shared, data, state, &block.inputs["SOUND_MENU"], script)
+    }
+    "sound_volume" => Value::Float(state.volume),
+    "music_getTempo" => Value::Float(shared.tempo),
+    "note" => aux_value(shared, data, state, &block.inputs["NOTE"], script),
+    "music_menu_DRUM" => aux_value(shared, data, state, &block.inputs["DRUM"], script),
+    "music_menu_INSTRUMENT" => {
+      aux_value(shared, data, state, &block.inputs["INSTRUMENT"], script)
+    }
+    "looks_costume" => aux_value(shared, data, state, &block.inputs["COSTUME"], script),
+    "looks_backdrops" => {
+      aux_value(shared, data, state, &block.inputs["BACKDROP"], script)
+    }
     "data_lengthoflist" => {
-      let Input::List(list) = &block.inputs["LIST"] else { panic!() };
-      if list.is_global {
-        panic!();
-      }
-      Value::Float(state.lists[list.id].len() as f64)
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!()
+      };
+      Value::Float(get_list(shared, state, list).len() as f64)
     }
     "data_itemoflist" => {
-      let Input::List(list) = &block.inputs["LIST"] else { panic!() };
-      if list.is_global {
-        panic!();
-      }
-      let list = &state.lists[list.id];
-      let index = aux_f64(shared, data, state, &block.inputs["INDEX"], script).floor();
-      if 0. < index && index <= list.len() as f64 {
-        list[index as usize - 1].clone()
-      } else {
-        Value::String(format!(""))
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!()
+      };
+      let index = aux_value(shared, data, state, &block.inputs["INDEX"], script);
+      let list = get_list(shared, state, list);
+      match list_index(&index, list.len(), false, &shared.random) {
+        ListIndex::Index(index) => list[index].clone(),
+        _ => Value::String(String::new()),
       }
     }
+    "data_itemnumoflist" => {
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!()
+      };
+      let item = aux_value(shared, data, state, &block.inputs["ITEM"], script);
+      let list = get_list(shared, state, list);
+      let index = list.iter().position(|value| value.compare(&item) == 0.);
+      Value::Float(index.map_or(0., |index| index as f64 + 1.))
+    }
+    "data_listcontainsitem" => {
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!()
+      };
+      let item = aux_value(shared, data, state, &block.inputs["ITEM"], script);
+      let list = get_list(shared, state, list);
+      Value::Bool(list.iter().any(|value| value.compare(&item) == 0.))
+    }
+    "data_listcontents" => {
+      let Input::List(list) = &block.inputs["LIST"] else {
+        panic!()
+      };
+      let list = get_list(shared, state, list);
+      // Lists of single characters are joined without spaces, like in Scratch.
+      let single_characters = list.iter().all(|value| match value {
+        Value::String(string) => string.chars().count() == 1,
+        _ => false,
+      });
+      let separator = if single_characters { "" } else { " " };
+      Value::String(
+        list
+          .iter()
+          .map(|value| value.to_string())
+          .collect::<Vec<_>>()
+          .join(separator),
+      )
+    }
     _ => {
       panic!("I don't know how to evaluate: {block:#?}")
     }
@@ -671,6 +1729,68 @@ fn get_variable<'a>(
   }
 }
 
+fn get_list<'a>(
+  shared: &'a SharedState,
+  state: &'a TargetState,
+  list: &ListInput,
+) -> &'a Vec<Value> {
+  if list.is_global {
+    &shared.global_lists[list.id]
+  } else {
+    &state.lists[list.id]
+  }
+}
+
+fn get_list_mut<'a>(
+  shared: &'a mut SharedState,
+  state: &'a mut TargetState,
+  list: &ListInput,
+) -> &'a mut Vec<Value> {
+  if list.is_global {
+    &mut shared.global_lists[list.id]
+  } else {
+    &mut state.lists[list.id]
+  }
+}
+
+/// Scratch refuses to grow a list past this many items.
+pub const LIST_ITEM_LIMIT: usize = 200000;
+
+#[derive(Debug, PartialEq)]
+enum ListIndex {
+  /// Counted from 0.
+  Index(usize),
+  All,
+  Invalid,
+}
+
+/// Converts a list block's index argument like Scratch's `Cast.toListIndex`: "last"
+/// and "random" (or "any") pick an item, "all" is only accepted if `accept_all`,
+/// numbers count from 1 and anything out of range is `ListIndex::Invalid`.
+fn list_index(
+  index: &Value,
+  length: usize,
+  accept_all: bool,
+  random: &Random,
+) -> ListIndex {
+  if let Value::String(index) = index {
+    match index.as_str() {
+      "all" if accept_all => return ListIndex::All,
+      "all" => return ListIndex::Invalid,
+      "last" if length > 0 => return ListIndex::Index(length - 1),
+      "random" | "any" if length > 0 => return ListIndex::Index(random.below(length)),
+      "last" | "random" | "any" => return ListIndex::Invalid,
+      _ => {}
+    }
+  }
+  let index = index.to_f64().floor();
+  if 1. <= index && index <= length as f64 {
+    ListIndex::Index(index as usize - 1)
+  } else {
+    ListIndex::Invalid
+  }
+}
+
 fn set_variable<'a, F: FnOnce(&Value) -> Value>(
   shared: &'a mut SharedState,
   state: &'a mut TargetState,
@@ -730,6 +1850,7 @@ fn aux_string(
     Input::Value(value) => value.to_string(),
     Input::Variable(variable) => get_variable(shared, state, variable).to_string(),
     Input::Argument(argument) => get_argument(*argument, script).to_string(),
+    Input::Broadcast(broadcast) => broadcast.name.clone(),
     _ => panic!(),
   }
 }
@@ -788,16 +1909,29 @@ fn aux_id(input: &Input) -> usize {
 
 #[derive(Debug)]
 pub struct TargetData {
+  pub name: String,
   pub is_stage: bool,
   pub blocks: Vec<Block>,
   pub custom_blocks: HashMap<String, CustomBlock>,
   pub costume_index_to_name: Vec<String>,
   pub costume_name_to_index: HashMap<String, usize>,
   pub costume_index_to_texture_index: HashMap<usize, usize>,
+  pub sound_name_to_index: HashMap<String, usize>,
+  /// Index in `Mixer::sounds` of each sound.
+  pub sound_index_to_sound_index: Vec<usize>,
+  /// Indices into `TargetState::variables`, or into the global variables for the stage.
+  pub variable_name_to_index: HashMap<String, usize>,
+  /// Indices into `TargetState::lists`, or into the global lists for the stage.
+  pub list_name_to_index: HashMap<String, usize>,
 }
 
 #[derive(Debug)]
-pub struct TargetState<'a> {
+pub struct TargetState {
+  /// Unique among the original targets and all clones.
+  pub id: usize,
+  pub is_clone: bool,
+  /// Set by `control_delete_this_clone`, the clone is removed after its scripts run.
+  pub deleted: bool,
   pub visible: bool,
   pub x: f64,
   pub y: f64,
@@ -809,34 +1943,192 @@ pub struct TargetState<'a> {
   pub volume: f64,
   pub variables: Vec<Value>,
   pub lists: Vec<Vec<Value>>,
-  pub say: Option<Say<'a>>,
+  pub say: Option<Say>,
+  pub effects: Effects,
+  pub sound_effects: SoundEffects,
+  /// Index in `music::INSTRUMENTS` of the instrument notes are played with.
+  pub instrument: usize,
   pub pen: PenState,
 }
 
-#[derive(Debug)]
+impl TargetState {
+  /// The state a new clone of this sprite starts with. Clones start without a speech
+  /// bubble and are given their `id` when they are added to the project.
+  pub fn clone_state(&self) -> TargetState {
+    TargetState {
+      id: 0,
+      is_clone: true,
+      deleted: false,
+      visible: self.visible,
+      x: self.x,
+      y: self.y,
+      size: self.size,
+      direction: self.direction,
+      draggable: self.draggable,
+      current_costume: self.current_costume,
+      rotation_style: self.rotation_style.clone(),
+      volume: self.volume,
+      variables: self.variables.clone(),
+      lists: self.lists.clone(),
+      say: None,
+      effects: self.effects.clone(),
+      sound_effects: self.sound_effects.clone(),
+      instrument: self.instrument,
+      pen: self.pen.clone(),
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
 pub struct PenState {
   pub is_down: bool,
-  pub size: u32,
-  pub r: u8,
-  pub g: u8,
-  pub b: u8,
-  pub a: u8,
+  pub size: f64,
+  /// Hue, from 0 to 100.
+  pub color: f64,
+  pub saturation: f64,
+  pub brightness: f64,
+  pub transparency: f64,
+  /// The Scratch 2 shade, from 0 to 200, kept for the legacy blocks.
+  pub shade: f64,
 
   pub x: f64,
   pub y: f64,
 }
 
-#[derive(Debug)]
+impl PenState {
+  /// Sets or changes one of the parameters of `pen_setPenColorParamTo`, wrapping the
+  /// hue and clamping the rest. Unknown parameters are ignored.
+  fn set_color_param(&mut self, param: &str, value: f64, change: bool) {
+    let (param, wrap) = match param {
+      "color" => (&mut self.color, true),
+      "saturation" => (&mut self.saturation, false),
+      "brightness" => (&mut self.brightness, false),
+      "transparency" => (&mut self.transparency, false),
+      _ => return,
+    };
+    let value = if change { *param + value } else { value };
+    *param = if wrap {
+      value.rem_euclid(100.)
+    } else {
+      value.clamp(0., 100.)
+    };
+  }
+
+  /// Recomputes the colour from the hue and shade, the way Scratch 2 did.
+  fn update_legacy_color(&mut self) {
+    let rgb = hsv_to_rgb((self.color / 100., 1., 1.));
+    let shade = if self.shade > 100. {
+      200. - self.shade
+    } else {
+      self.shade
+    };
+    let rgb = if shade < 50. {
+      let fraction = ((10. + shade) / 60.).clamp(0., 1.);
+      rgb.map(|channel| channel * fraction)
+    } else {
+      let fraction = ((shade - 50.) / 60.).clamp(0., 1.);
+      rgb.map(|channel| channel + (1. - channel) * fraction)
+    };
+    let (hue, saturation, value) = rgb_to_hsv(rgb);
+    self.color = hue * 100.;
+    self.saturation = saturation * 100.;
+    self.brightness = value * 100.;
+  }
+
+  /// The colour to draw with.
+  pub fn rgba(&self) -> [u8; 4] {
+    let [r, g, b] = hsv_to_rgb((
+      self.color / 100.,
+      self.saturation / 100.,
+      self.brightness / 100.,
+    ));
+    let a = 1. - self.transparency / 100.;
+    [r, g, b, a].map(|channel| (channel * 255.).floor() as u8)
+  }
+}
+
+#[derive(Debug, Clone)]
 pub enum RotationStyle {
   AllAround,
   LeftRight,
   DontRotate,
 }
 
-#[derive(Derivative)]
-#[derivative(Debug)]
-pub struct Say<'a> {
+/// Scratch cuts bubble text after this many characters.
+pub const SAY_LIMIT: usize = 330;
+
+#[derive(Debug)]
+pub struct Say {
+  pub kind: SayKind,
   pub message: String,
-  #[derivative(Debug = "ignore")]
-  pub texture: Option<sdl2Texture<'a>>,
+  /// `SharedState::time` when a `looks_sayforsecs` or `looks_thinkforsecs` bubble
+  /// goes away, unless another bubble replaces it first.
+  pub deadline: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn index(index: &str, length: usize, accept_all: bool) -> ListIndex {
+    let random = Random::new(0);
+    list_index(
+      &Value::String(index.to_string()),
+      length,
+      accept_all,
+      &random,
+    )
+  }
+
+  #[test]
+  fn list_index_last() {
+    assert_eq!(index("last", 3, false), ListIndex::Index(2));
+    assert_eq!(index("last", 0, false), ListIndex::Invalid);
+  }
+
+  #[test]
+  fn list_index_random() {
+    let random = Random::new(0);
+    for name in ["random", "any"] {
+      let name = Value::String(name.to_string());
+      let mut seen = [false; 3];
+      for _ in 0..100 {
+        match list_index(&name, 3, false, &random) {
+          ListIndex::Index(index) => seen[index] = true,
+          index => panic!("{index:?}"),
+        }
+      }
+      assert_eq!(seen, [true; 3]);
+      assert_eq!(list_index(&name, 0, false, &random), ListIndex::Invalid);
+    }
+  }
+
+  #[test]
+  fn list_index_all() {
+    assert_eq!(index("all", 3, true), ListIndex::All);
+    assert_eq!(index("all", 0, true), ListIndex::All);
+    assert_eq!(index("all", 3, false), ListIndex::Invalid);
+  }
+
+  #[test]
+  fn list_index_numbers() {
+    let random = Random::new(0);
+    let number = |number| list_index(&Value::Float(number), 3, false, &random);
+    assert_eq!(number(1.), ListIndex::Index(0));
+    assert_eq!(number(3.), ListIndex::Index(2));
+    assert_eq!(number(2.9), ListIndex::Index(1));
+    assert_eq!(number(0.), ListIndex::Invalid);
+    assert_eq!(number(-1.), ListIndex::Invalid);
+    assert_eq!(number(4.), ListIndex::Invalid);
+    assert_eq!(number(f64::NAN), ListIndex::Invalid);
+  }
+
+  #[test]
+  fn list_index_numeric_strings() {
+    assert_eq!(index("2", 3, false), ListIndex::Index(1));
+    assert_eq!(index("1.5", 3, false), ListIndex::Index(0));
+    assert_eq!(index("4", 3, false), ListIndex::Invalid);
+    assert_eq!(index("first", 3, false), ListIndex::Invalid);
+    assert_eq!(index("", 3, false), ListIndex::Invalid);
+  }
 }
@@ -0,0 +1,681 @@
+use std::borrow::Cow;
+
+use crate::{
+  effects::{apply_effects, Effects},
+  pen::Stamp,
+  project::{SharedState, Texture},
+  target::{TargetData, TargetState},
+};
+
+/// How a sprite, clone or the stage looks, for the sensing blocks of other sprites
+/// and clones.
+#[derive(Debug)]
+pub struct Drawable {
+  /// Index in `Project::targets`.
+  pub target: usize,
+  pub is_stage: bool,
+  pub is_clone: bool,
+  pub visible: bool,
+  pub stamp: Stamp,
+  /// The costume with every effect but ghost applied, `None` without such effects.
+  pixels: Option<Vec<u8>>,
+}
+
+/// Updates the drawable of `state`, a state of the target at `target` in
+/// `Project::targets`. Effects are only applied to the costume again if they or the
+/// costume changed.
+pub fn record_drawable(
+  shared: &mut SharedState,
+  target: usize,
+  data: &TargetData,
+  state: &TargetState,
+) {
+  let stamp = Stamp::new(data, state);
+  let pixels = match shared.drawables.remove(&state.id) {
+    Some(previous) if same_pixels(&previous.stamp, &stamp) => previous.pixels,
+    _ => effect_pixels(&shared.textures[stamp.texture], &stamp.effects),
+  };
+  let drawable = Drawable {
+    target,
+    is_stage: data.is_stage,
+    is_clone: state.is_clone,
+    visible: state.visible,
+    stamp,
+    pixels,
+  };
+  shared.drawables.insert(state.id, drawable);
+}
+
+/// Every effect but ghost, which does not change which pixels are touched.
+fn opaque_effects(effects: &Effects) -> Effects {
+  Effects {
+    ghost: 0.,
+    ..effects.clone()
+  }
+}
+
+fn same_pixels(stamp: &Stamp, other: &Stamp) -> bool {
+  stamp.texture == other.texture
+    && opaque_effects(&stamp.effects) == opaque_effects(&other.effects)
+}
+
+fn effect_pixels(texture: &Texture, effects: &Effects) -> Option<Vec<u8>> {
+  let effects = opaque_effects(effects);
+  (!effects.is_empty()).then(|| apply_effects(texture, &effects))
+}
+
+/// A costume placed on the stage the way the touching blocks see it, with every
+/// effect but ghost applied.
+struct Silhouette<'a> {
+  stamp: &'a Stamp,
+  texture: &'a Texture,
+  pixels: Cow<'a, [u8]>,
+}
+
+impl<'a> Silhouette<'a> {
+  /// The silhouette of another target, as it was last recorded.
+  fn new(drawable: &'a Drawable, textures: &'a [Texture]) -> Silhouette<'a> {
+    let texture = &textures[drawable.stamp.texture];
+    let pixels = drawable.pixels.as_deref().unwrap_or(&texture.pixels);
+    Silhouette {
+      stamp: &drawable.stamp,
+      texture,
+      pixels: Cow::Borrowed(pixels),
+    }
+  }
+
+  /// The silhouette of the target running a block, as it is right now. The pixels of
+  /// its drawable are reused if its costume and effects have not changed since.
+  fn live(shared: &'a SharedState, stamp: &'a Stamp, id: usize) -> Silhouette<'a> {
+    let texture = &shared.textures[stamp.texture];
+    let pixels = match shared.drawables.get(&id) {
+      Some(drawable) if same_pixels(&drawable.stamp, stamp) => {
+        Cow::Borrowed(drawable.pixels.as_deref().unwrap_or(&texture.pixels))
+      }
+      _ => match effect_pixels(texture, &stamp.effects) {
+        Some(pixels) => Cow::Owned(pixels),
+        None => Cow::Borrowed(&texture.pixels[..]),
+      },
+    };
+    Silhouette {
+      stamp,
+      texture,
+      pixels,
+    }
+  }
+
+  /// The colour at the given stage coordinates, if the costume covers them.
+  fn color(&self, x: f64, y: f64) -> Option<[u8; 4]> {
+    let (u, v) = self.stamp.texel(self.texture, x, y)?;
+    let index = ((v * self.texture.width + u) * 4) as usize;
+    let color: [u8; 4] = self.pixels[index..index + 4].try_into().unwrap();
+    (color[3] > 0).then_some(color)
+  }
+
+  fn bounds(&self) -> (f64, f64, f64, f64) {
+    self.stamp.bounds(self.texture)
+  }
+}
+
+/// Whether a sprite touches the mouse pointer ("_mouse_"), the edge of the stage
+/// ("_edge_"), or any visible sprite or clone with the given name. Hidden sprites
+/// only touch the edge.
+pub fn touching_object(
+  shared: &SharedState,
+  data: &TargetData,
+  state: &TargetState,
+  object: &str,
+) -> bool {
+  if data.is_stage {
+    return false;
+  }
+  let stamp = Stamp::new(data, state);
+  let silhouette = Silhouette::live(shared, &stamp, state.id);
+  match object {
+    "_edge_" => {
+      let (left, right, bottom, top) = silhouette.bounds();
+      let (half_width, half_height) =
+        (shared.stage_width / 2., shared.stage_height / 2.);
+      left < -half_width
+        || right > half_width
+        || bottom < -half_height
+        || top > half_height
+    }
+    _ if !state.visible => false,
+    "_mouse_" => silhouette.color(shared.mouse_x, shared.mouse_y).is_some(),
+    name => {
+      let Some(&target) = shared.target_name_to_target_index.get(name) else {
+        return false;
+      };
+      shared.drawables.iter().any(|(&id, drawable)| {
+        if id == state.id
+          || drawable.target != target
+          || drawable.is_stage
+          || !drawable.visible
+        {
+          return false;
+        }
+        let texture = &shared.textures[drawable.stamp.texture];
+        if !overlaps(silhouette.bounds(), drawable.stamp.bounds(texture)) {
+          return false;
+        }
+        let other = Silhouette::new(drawable, &shared.textures);
+        let bounds = intersect(silhouette.bounds(), other.bounds());
+        stage_pixels(shared, bounds).any(|(_, x, y)| {
+          silhouette.color(x, y).is_some() && other.color(x, y).is_some()
+        })
+      })
+    }
+  }
+}
+
+/// Whether any pixel of a sprite is drawn over `color`, looking at everything else
+/// on the stage. With a `mask`, only the pixels of the sprite that have that colour
+/// count.
+pub fn touching_color(
+  shared: &SharedState,
+  data: &TargetData,
+  state: &TargetState,
+  color: [u8; 3],
+  mask: Option<[u8; 3]>,
+) -> bool {
+  if data.is_stage || !state.visible {
+    return false;
+  }
+  let stamp = Stamp::new(data, state);
+  let silhouette = Silhouette::live(shared, &stamp, state.id);
+  let bounds = silhouette.bounds();
+  // Everything else that can be under the sprite, from the top layer down, with its
+  // opacity. `None` is the pen layer.
+  let mut layers = Vec::new();
+  for id in shared.layers.iter().rev() {
+    let Some(drawable) = shared.drawables.get(id) else {
+      continue;
+    };
+    if *id == state.id || !drawable.visible {
+      continue;
+    }
+    let texture = &shared.textures[drawable.stamp.texture];
+    if drawable.is_stage {
+      // The pen layer is drawn between the stage and the sprites.
+      layers.push((None, 1.));
+    } else if !overlaps(bounds, drawable.stamp.bounds(texture)) {
+      continue;
+    }
+    let opacity = 1. - drawable.stamp.effects.ghost / 100.;
+    layers.push((Some(Silhouette::new(drawable, &shared.textures)), opacity));
+  }
+  stage_pixels(shared, bounds).any(|(index, x, y)| {
+    let Some(own) = silhouette.color(x, y) else {
+      return false;
+    };
+    if mask.is_some_and(|mask| !mask_matches([own[0], own[1], own[2]], mask)) {
+      return false;
+    }
+    // Blended like scratch-render does, on a white background.
+    let mut blended = [0.; 3];
+    let mut remaining = 1.;
+    for (layer, opacity) in &layers {
+      let below = match layer {
+        Some(silhouette) => silhouette.color(x, y),
+        None => shared.pen_layer.pixels[index * 4..index * 4 + 4]
+          .try_into()
+          .ok(),
+      };
+      let Some(below) = below else {
+        continue;
+      };
+      let alpha = below[3] as f64 / 255. * opacity;
+      for (blended, channel) in blended.iter_mut().zip(below) {
+        *blended += channel as f64 * alpha * remaining;
+      }
+      remaining *= 1. - alpha;
+      if remaining == 0. {
+        break;
+      }
+    }
+    color_matches(
+      blended.map(|channel| (channel + remaining * 255.).round() as u8),
+      color,
+    )
+  })
+}
+
+/// Whether a colour on the stage counts as `color`, comparing the top 5 bits of red
+/// and green and the top 4 bits of blue like scratch-render.
+fn color_matches(stage: [u8; 3], color: [u8; 3]) -> bool {
+  stage[0] & 0b11111000 == color[0] & 0b11111000
+    && stage[1] & 0b11111000 == color[1] & 0b11111000
+    && stage[2] & 0b11110000 == color[2] & 0b11110000
+}
+
+/// Whether a colour of the sprite counts as the `mask` of "color is touching
+/// color", comparing the top 6 bits of every channel like scratch-render.
+fn mask_matches(own: [u8; 3], mask: [u8; 3]) -> bool {
+  (0..3).all(|channel| own[channel] & 0b11111100 == mask[channel] & 0b11111100)
+}
+
+/// Whether a non-transparent pixel of the costume of `state` covers the given stage
+/// coordinates.
+pub fn touches_point(
+  shared: &SharedState,
+  data: &TargetData,
+  state: &TargetState,
+  x: f64,
+  y: f64,
+) -> bool {
+  let stamp = Stamp::new(data, state);
+  state.visible
+    && Silhouette::live(shared, &stamp, state.id)
+      .color(x, y)
+      .is_some()
+}
+
+fn intersect(
+  (left, right, bottom, top): (f64, f64, f64, f64),
+  (other_left, other_right, other_bottom, other_top): (f64, f64, f64, f64),
+) -> (f64, f64, f64, f64) {
+  (
+    left.max(other_left),
+    right.min(other_right),
+    bottom.max(other_bottom),
+    top.min(other_top),
+  )
+}
+
+fn overlaps(bounds: (f64, f64, f64, f64), other: (f64, f64, f64, f64)) -> bool {
+  let (left, right, bottom, top) = intersect(bounds, other);
+  left < right && bottom < top
+}
+
+/// The pixels of the stage inside `bounds`, as the index of the pixel and the stage
+/// coordinates of its centre.
+fn stage_pixels(
+  shared: &SharedState,
+  (left, right, bottom, top): (f64, f64, f64, f64),
+) -> impl Iterator<Item = (usize, f64, f64)> {
+  let (width, height) = (shared.stage_width, shared.stage_height);
+  let clip = |value: f64, max: f64| value.clamp(0., max) as usize;
+  let columns =
+    clip((left + width / 2.).floor(), width)..clip((right + width / 2.).ceil(), width);
+  let rows = clip((height / 2. - top).floor(), height)
+    ..clip((height / 2. - bottom).ceil(), height);
+  rows.flat_map(move |row| {
+    columns.clone().map(move |column| {
+      (
+        row * width as usize + column,
+        column as f64 + 0.5 - width / 2.,
+        height / 2. - (row as f64 + 0.5),
+      )
+    })
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::{
+    random::Random,
+    sound::SoundEffects,
+    target::{PenState, RotationStyle},
+  };
+
+  const RED: [u8; 4] = [255, 0, 0, 255];
+  const GREEN: [u8; 4] = [0, 255, 0, 255];
+  const BLUE: [u8; 4] = [0, 0, 255, 255];
+  const CLEAR: [u8; 4] = [0, 0, 0, 0];
+
+  fn texture<F: Fn(u32, u32) -> [u8; 4]>(width: u32, height: u32, pixel: F) -> Texture {
+    let pixels = (0..height)
+      .flat_map(|v| (0..width).map(move |u| (u, v)))
+      .flat_map(|(u, v)| pixel(u, v))
+      .collect();
+    Texture::new(1, width, height, pixels, 0., 0.)
+  }
+
+  fn square(size: u32, color: [u8; 4]) -> Texture {
+    texture(size, size, |_, _| color)
+  }
+
+  /// A square with a transparent 3 by 3 pixel square in every corner.
+  fn rounded(size: u32) -> Texture {
+    texture(size, size, |u, v| {
+      let corner = |i: u32| i < 3 || i >= size - 3;
+      if corner(u) && corner(v) {
+        CLEAR
+      } else {
+        RED
+      }
+    })
+  }
+
+  /// The stage, with a transparent backdrop, and sprites with one costume each.
+  struct World {
+    shared: SharedState,
+    targets: Vec<TargetData>,
+    /// Every original and clone with the index of its target, by `TargetState::id`.
+    states: Vec<(usize, TargetState)>,
+  }
+
+  impl World {
+    fn new() -> World {
+      let shared = SharedState::new(480, 360, Random::new(0));
+      let mut world = World {
+        shared,
+        targets: Vec::new(),
+        states: Vec::new(),
+      };
+      world.add("Stage", square(2, CLEAR), 0., 0.);
+      world.targets[0].is_stage = true;
+      world
+    }
+
+    /// Adds a sprite on top of the others, returning its `TargetState::id`.
+    fn add(&mut self, name: &str, texture: Texture, x: f64, y: f64) -> usize {
+      let index = self.targets.len();
+      let texture_index = self.shared.textures.len();
+      self.shared.textures.push(texture);
+      let data = TargetData {
+        name: name.to_string(),
+        is_stage: false,
+        blocks: Vec::new(),
+        custom_blocks: HashMap::new(),
+        costume_index_to_name: Vec::new(),
+        costume_name_to_index: HashMap::new(),
+        costume_index_to_texture_index: HashMap::from([(0, texture_index)]),
+        sound_name_to_index: HashMap::new(),
+        sound_index_to_sound_index: Vec::new(),
+        variable_name_to_index: HashMap::new(),
+        list_name_to_index: HashMap::new(),
+      };
+      let id = self.states.len();
+      let state = TargetState {
+        id,
+        is_clone: false,
+        deleted: false,
+        visible: true,
+        x,
+        y,
+        size: 100.,
+        direction: 90.,
+        draggable: false,
+        current_costume: 0,
+        rotation_style: RotationStyle::AllAround,
+        volume: 100.,
+        variables: Vec::new(),
+        lists: Vec::new(),
+        say: None,
+        effects: Effects::default(),
+        sound_effects: SoundEffects::default(),
+        instrument: 0,
+        pen: PenState {
+          is_down: false,
+          size: 1.,
+          color: 66.66,
+          saturation: 100.,
+          brightness: 100.,
+          transparency: 0.,
+          shade: 50.,
+          x,
+          y,
+        },
+      };
+      self
+        .shared
+        .target_name_to_target_index
+        .insert(name.to_string(), index);
+      self.shared.layers.push(id);
+      self.targets.push(data);
+      self.states.push((index, state));
+      id
+    }
+
+    /// Adds a clone of the original or clone `id` on top of the others.
+    fn add_clone(&mut self, id: usize, x: f64, y: f64) -> usize {
+      let (index, state) = &self.states[id];
+      let mut clone = state.clone_state();
+      clone.id = self.states.len();
+      (clone.x, clone.y) = (x, y);
+      self.shared.layers.push(clone.id);
+      self.states.push((*index, clone));
+      self.states.len() - 1
+    }
+
+    fn state(&mut self, id: usize) -> &mut TargetState {
+      &mut self.states[id].1
+    }
+
+    fn record(&mut self) {
+      for (index, state) in &self.states {
+        record_drawable(&mut self.shared, *index, &self.targets[*index], state);
+      }
+    }
+
+    fn get(&self, id: usize) -> (&TargetData, &TargetState) {
+      let (index, state) = &self.states[id];
+      (&self.targets[*index], state)
+    }
+
+    fn touching(&mut self, index: usize, object: &str) -> bool {
+      self.record();
+      let (data, state) = self.get(index);
+      touching_object(&self.shared, data, state, object)
+    }
+
+    fn touching_color(&mut self, index: usize, color: [u8; 3]) -> bool {
+      self.record();
+      let (data, state) = self.get(index);
+      touching_color(&self.shared, data, state, color, None)
+    }
+
+    fn color_touching(&mut self, index: usize, mask: [u8; 3], color: [u8; 3]) -> bool {
+      self.record();
+      let (data, state) = self.get(index);
+      touching_color(&self.shared, data, state, color, Some(mask))
+    }
+  }
+
+  #[test]
+  fn opaque_squares_touch() {
+    let mut world = World::new();
+    let a = world.add("A", square(10, RED), 0., 0.);
+    let b = world.add("B", square(10, BLUE), 8., 8.);
+    assert!(world.touching(a, "B"));
+    assert!(world.touching(b, "A"));
+    world.state(b).x = 11.;
+    assert!(!world.touching(a, "B"));
+    assert!(!world.touching(a, "C"));
+  }
+
+  #[test]
+  fn transparent_corners_do_not_touch() {
+    let mut world = World::new();
+    let a = world.add("A", rounded(10), 0., 0.);
+    let b = world.add("B", rounded(10), 8., 8.);
+    assert!(!world.touching(a, "B"));
+    world.state(b).x = 6.;
+    world.state(b).y = 6.;
+    assert!(world.touching(a, "B"));
+  }
+
+  #[test]
+  fn hidden_sprites_do_not_touch() {
+    let mut world = World::new();
+    let a = world.add("A", square(10, RED), 0., 0.);
+    let b = world.add("B", square(10, BLUE), 5., 0.);
+    world.state(b).visible = false;
+    assert!(!world.touching(a, "B"));
+    world.state(b).visible = true;
+    world.state(a).visible = false;
+    assert!(!world.touching(a, "B"));
+  }
+
+  #[test]
+  fn clones_touch_their_own_sprite() {
+    let mut world = World::new();
+    let a = world.add("A", square(10, RED), 0., 0.);
+    let clone = world.add_clone(a, 5., 0.);
+    let far = world.add_clone(a, 100., 0.);
+    assert!(world.touching(a, "A"));
+    assert!(world.touching(clone, "A"));
+    assert!(!world.touching(far, "A"));
+    world.state(clone).x = 95.;
+    assert!(world.touching(far, "A"));
+  }
+
+  #[test]
+  fn rotation_styles() {
+    let mut world = World::new();
+    let bar = world.add("Bar", texture(20, 2, |_, _| RED), 0., 0.);
+    world.add("Top", square(2, BLUE), 0., 8.);
+    world.add("Right", square(2, BLUE), 8., 0.);
+    // Pointing up, so the bar stands upright.
+    world.state(bar).direction = 0.;
+    assert!(world.touching(bar, "Top"));
+    assert!(!world.touching(bar, "Right"));
+    world.state(bar).rotation_style = RotationStyle::LeftRight;
+    assert!(!world.touching(bar, "Top"));
+    assert!(world.touching(bar, "Right"));
+    // Diagonal, the bounds overlap the squares but the bar does not.
+    world.state(bar).rotation_style = RotationStyle::AllAround;
+    world.state(bar).direction = 45.;
+    assert!(!world.touching(bar, "Top"));
+    assert!(!world.touching(bar, "Right"));
+  }
+
+  #[test]
+  fn edge() {
+    let mut world = World::new();
+    let a = world.add("A", square(20, RED), 0., 0.);
+    assert!(!world.touching(a, "_edge_"));
+    world.state(a).x = 235.;
+    assert!(world.touching(a, "_edge_"));
+    // Hidden sprites still touch the edge.
+    world.state(a).visible = false;
+    assert!(world.touching(a, "_edge_"));
+  }
+
+  #[test]
+  fn edge_ignores_transparent_padding() {
+    let mut world = World::new();
+    let padded = texture(40, 40, |u, v| {
+      let inside = |i: u32| (15..25).contains(&i);
+      if inside(u) && inside(v) {
+        RED
+      } else {
+        CLEAR
+      }
+    });
+    let a = world.add("A", padded, 230., 0.);
+    assert!(!world.touching(a, "_edge_"));
+    world.state(a).x = 236.;
+    assert!(world.touching(a, "_edge_"));
+  }
+
+  #[test]
+  fn mouse() {
+    let mut world = World::new();
+    let a = world.add("A", rounded(10), 0., 0.);
+    world.shared.mouse_x = 2.;
+    world.shared.mouse_y = 2.;
+    assert!(world.touching(a, "_mouse_"));
+    // In a transparent corner.
+    world.shared.mouse_x = 4.5;
+    world.shared.mouse_y = 4.5;
+    assert!(!world.touching(a, "_mouse_"));
+    world.shared.mouse_x = 20.;
+    world.shared.mouse_y = 0.;
+    assert!(!world.touching(a, "_mouse_"));
+    world.record();
+    let (data, state) = world.get(a);
+    assert!(touches_point(&world.shared, data, state, 0., 0.));
+    assert!(!touches_point(&world.shared, data, state, 4.5, -4.5));
+  }
+
+  #[test]
+  fn touching_color_under_a_ghost() {
+    let mut world = World::new();
+    let a = world.add("A", square(10, BLUE), 0., 0.);
+    let b = world.add("B", square(10, RED), 0., 0.);
+    assert!(world.touching_color(a, [255, 0, 0]));
+    world.state(b).effects.ghost = 50.;
+    assert!(!world.touching_color(a, [255, 0, 0]));
+    // Half red over the white background.
+    assert!(world.touching_color(a, [255, 128, 128]));
+    world.state(b).effects.ghost = 100.;
+    assert!(world.touching_color(a, [255, 255, 255]));
+    // The sprite itself does not count, nor does the ghost.
+    assert!(!world.touching_color(a, [0, 0, 255]));
+    assert!(!world.touching_color(a, [255, 0, 0]));
+  }
+
+  #[test]
+  fn touching_color_with_effects() {
+    let mut world = World::new();
+    let a = world.add("A", square(10, BLUE), 0., 0.);
+    let b = world.add("B", square(10, RED), 0., 0.);
+    // Brightness 100 turns every colour white.
+    world.state(b).effects.brightness = 100.;
+    assert!(world.touching_color(a, [255, 255, 255]));
+    assert!(!world.touching_color(a, [255, 0, 0]));
+  }
+
+  #[test]
+  fn color_is_touching_color() {
+    let mut world = World::new();
+    // Green on the left half, blue on the right.
+    let halves = texture(10, 10, |u, _| if u < 5 { GREEN } else { BLUE });
+    let a = world.add("A", halves, 0., 0.);
+    world.add("B", square(4, RED), 4., 0.);
+    assert!(world.color_touching(a, [0, 0, 255], [255, 0, 0]));
+    assert!(!world.color_touching(a, [0, 255, 0], [255, 0, 0]));
+    // The mask only compares the top 6 bits.
+    assert!(world.color_touching(a, [0, 0, 0b11111100], [255, 0, 0]));
+    assert!(!world.color_touching(a, [0, 0, 0b11111000], [255, 0, 0]));
+  }
+
+  #[test]
+  fn color_tolerance() {
+    // 6 bits for the mask.
+    assert!(mask_matches([0b11111111; 3], [0b11111100; 3]));
+    assert!(!mask_matches([0b11111111; 3], [0b11111000; 3]));
+    // 5 bits of red and green and 4 of blue for the colour on the stage.
+    assert!(color_matches(
+      [0b11111111; 3],
+      [0b11111000, 0b11111000, 0b11110000]
+    ));
+    assert!(!color_matches(
+      [0b11111111; 3],
+      [0b11110000, 0b11111000, 0b11110000]
+    ));
+    assert!(!color_matches(
+      [0b11111111; 3],
+      [0b11111000, 0b11110000, 0b11110000]
+    ));
+    assert!(!color_matches(
+      [0b11111111; 3],
+      [0b11111000, 0b11111000, 0b11100000]
+    ));
+  }
+
+  #[test]
+  fn effect_pixels_are_reused() {
+    let mut world = World::new();
+    let a = world.add("A", square(10, RED), 0., 0.);
+    world.state(a).effects.color = 50.;
+    world.record();
+    let pixels = world.shared.drawables[&a].pixels.as_ref().unwrap().as_ptr();
+    world.state(a).x = 20.;
+    world.state(a).effects.ghost = 30.;
+    world.record();
+    assert_eq!(
+      world.shared.drawables[&a].pixels.as_ref().unwrap().as_ptr(),
+      pixels
+    );
+    world.state(a).effects.color = 0.;
+    world.record();
+    assert!(world.shared.drawables[&a].pixels.is_none());
+  }
+}